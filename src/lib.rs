@@ -0,0 +1,4907 @@
+//! Aggregates the rows of Cliff Click's stock CSV benchmark by product, with
+//! a variety of strategies for reading and parsing the input to compare.
+//!
+//! [`aggregate`] aggregates a single reader, for embedding in other tools,
+//! and [`run_strategy`] runs a named strategy over paths, as the binary does.
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, Stdin, Write};
+use std::iter;
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use flate2::read::GzDecoder;
+// Hashes with aHash by default, which is much faster than SipHash for short
+// product symbols.
+use hashbrown::HashMap;
+use memchr::{memchr, memchr2_iter, memchr_iter, memrchr};
+#[cfg(unix)]
+use memmap2::Advice;
+use memmap2::Mmap;
+use rayon::prelude::*;
+
+pub mod phases;
+
+/// The names of all strategies, in the order they are listed in the usage.
+pub const STRATEGIES: &[&str] = &[
+    "fulltext",
+    "fulltext-reuse",
+    "fulltext-parallel",
+    "memmap-ref",
+    "memmap-clone",
+    "memmap-seq",
+    "memmap-memchr",
+    "memmap-parallel",
+    "read",
+    "read-memmap",
+    "read-memchr",
+    "custom-read",
+    "block-read",
+    "simd",
+    "pipeline",
+    "read-offsets",
+    "bstr",
+];
+
+/// Aggregates the rows of a single reader by product, like the `custom-read`
+/// strategy, and returns the products instead of printing them.
+pub fn aggregate<R: Read>(
+    reader: R,
+    config: &Config,
+) -> Result<HashMap<Box<[u8]>, ProductData>, Error> {
+    let reader = Cell::new(Some(reader));
+    let open = |_: &Path| Ok(reader.take().expect("the input is only opened once"));
+    let mut sink = Collect::default();
+    calc_custom_read(&[PathBuf::new()], open, config, &mut sink)?;
+    Ok(sink
+        .products
+        .into_iter()
+        .map(|(prod, data)| (prod.into_boxed_slice(), data))
+        .collect())
+}
+
+/// Runs the named strategy over the inputs and passes the products to the
+/// sink.
+#[inline]
+pub fn run_strategy<S: Sink>(
+    strategy: &str,
+    inputs: &[PathBuf],
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    if !STRATEGIES.contains(&strategy) {
+        return Err(Error::UnknownStrategy(strategy.to_owned()));
+    }
+    let config = &*sniff_inputs(inputs, config)?;
+    let open = |path: &Path| Ok(Input::open(path)?);
+    let mmap = |path: &Path| Ok(Cursor::new(memmap(path)?));
+    // read-memmap reads like read, but maps its inputs, so cannot read stdin.
+    let streaming = matches!(
+        strategy,
+        "read" | "read-memchr" | "custom-read" | "block-read" | "pipeline" | "read-offsets"
+    );
+    if let Some(interval) = config.follow {
+        if strategy != "read" {
+            return Err(Error::Unsupported(
+                "only the read strategy supports --follow",
+            ));
+        }
+        let [path] = inputs else {
+            return Err(Error::Unsupported("--follow only follows a single input"));
+        };
+        return calc_follow(path, interval, config, sink);
+    }
+    if config.progress && matches!(strategy, "fulltext-parallel" | "memmap-parallel") {
+        return Err(Error::Unsupported(
+            "the parallel strategies do not support --progress",
+        ));
+    }
+    if config.count_only && !(streaming || strategy == "read-memmap") {
+        return Err(Error::Unsupported(
+            "only the streaming strategies and read-memmap support --count-only",
+        ));
+    }
+    let copies_keys = streaming || matches!(strategy, "read-memmap" | "memmap-clone" | "bstr");
+    if config.group_by_side && !copies_keys {
+        return Err(Error::Unsupported(
+            "only the strategies which copy keys support grouping by B/S",
+        ));
+    }
+    let borrows_keys = matches!(
+        strategy,
+        "fulltext" | "fulltext-reuse" | "memmap-ref" | "memmap-seq"
+    );
+    if config.normalize_keys && !(copies_keys || borrows_keys) {
+        return Err(Error::Unsupported(
+            "only the strategies which copy keys or fall back to it support --normalize-keys",
+        ));
+    }
+    match strategy {
+        "fulltext" => calc_key_ref(read_fulltext(single_input(inputs)?)?, config, sink),
+        "fulltext-reuse" => {
+            let mut text = FULLTEXT_BUF.lock().unwrap();
+            read_fulltext_into(single_input(inputs)?, &mut text)?;
+            calc_key_ref(&text[..], config, sink)
+        }
+        "fulltext-parallel" => {
+            calc_key_ref_parallel(read_fulltext(single_input(inputs)?)?, config, sink)
+        }
+        "memmap-ref" => calc_key_ref(memmap(single_input(inputs)?)?, config, sink),
+        "memmap-clone" => calc_key_clone(memmap(single_input(inputs)?)?, config, sink),
+        "memmap-seq" => calc_key_ref(memmap_sequential(single_input(inputs)?)?, config, sink),
+        "memmap-memchr" => calc_memmap_memchr(memmap(single_input(inputs)?)?, config, sink),
+        "memmap-parallel" => calc_key_ref_parallel(memmap(single_input(inputs)?)?, config, sink),
+        "read" => calc_read(inputs, open, config, sink),
+        "read-memmap" => calc_read(inputs, mmap, config, sink),
+        "read-memchr" => calc_read_memchr(inputs, open, config, sink),
+        "custom-read" => calc_custom_read(inputs, open, config, sink),
+        "block-read" => calc_block_read(inputs, open, config, sink),
+        "simd" => calc_simd(memmap(single_input(inputs)?)?, config, sink),
+        "pipeline" => calc_pipeline(inputs, open, config, sink),
+        "read-offsets" => calc_read_offsets(inputs, open, config, sink),
+        "bstr" => calc_bstr(memmap(single_input(inputs)?)?, config, sink),
+        _ => unreachable!("checked against STRATEGIES"),
+    }
+}
+
+/// Detects the delimiter of the inputs with `--delimiter-auto`, from the
+/// header of the first.
+fn sniff_inputs<'a>(inputs: &[PathBuf], config: &'a Config) -> Result<Cow<'a, Config>, Error> {
+    if !config.delimiter_auto {
+        return Ok(Cow::Borrowed(config));
+    }
+    let path = inputs.first().ok_or(HeaderError::Empty)?;
+    let delimiter = sniff_delimiter(&peek_header(path, config)?)?;
+    Ok(Cow::Owned(config.with_delimiter(delimiter)))
+}
+
+/// The delimiters which `--delimiter-auto` chooses between.
+const SNIFFED_DELIMITERS: [u8; 4] = [b',', b'\t', b'|', b';'];
+
+/// Detects the delimiter of a header for `--delimiter-auto`, as the most
+/// frequent of `SNIFFED_DELIMITERS`. A tie, including when none occur, is
+/// ambiguous.
+pub fn sniff_delimiter(header: &[u8]) -> Result<u8, HeaderError> {
+    let counts = SNIFFED_DELIMITERS.map(|delim| memchr_iter(delim, header).count());
+    let max = counts.into_iter().max().unwrap();
+    let tied: Vec<u8> = SNIFFED_DELIMITERS
+        .into_iter()
+        .zip(counts)
+        .filter(|&(_, count)| count == max)
+        .map(|(delim, _)| delim)
+        .collect();
+    match *tied {
+        [delim] if max != 0 => Ok(delim),
+        _ => Err(HeaderError::AmbiguousDelimiter(tied)),
+    }
+}
+
+/// Reads the first line of an input to sniff its delimiter, leaving it to be
+/// read again by the strategy. Stdin cannot be reopened, so only what it has
+/// buffered is peeked at, which holds the header unless it is unusually long
+/// or arrives in pieces.
+fn peek_header(path: &Path, config: &Config) -> Result<Vec<u8>, Error> {
+    let mut header = Vec::new();
+    if is_stdin(path) {
+        let mut stdin = io::stdin().lock();
+        let buf = skip_rows(stdin.fill_buf()?, config);
+        header.extend_from_slice(&buf[..memchr(b'\n', buf).unwrap_or(buf.len())]);
+    } else {
+        let mut reader = BufReader::new(Input::open(path)?);
+        skip_rows_buffered(&mut reader, config)?;
+        reader.read_until(b'\n', &mut header)?;
+    }
+    if header.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    Ok(header)
+}
+
+/// Returns the only input, for the strategies which cannot aggregate several.
+fn single_input(inputs: &[PathBuf]) -> Result<&Path, Error> {
+    match inputs {
+        [input] => Ok(input),
+        _ => Err(Error::Unsupported(
+            "only the streaming strategies and read-memmap support multiple inputs",
+        )),
+    }
+}
+
+/// Runs `f` on each input in turn. When there are several inputs, errors
+/// are attributed to the input in which they occurred.
+fn for_each_input(
+    inputs: &[PathBuf],
+    mut f: impl FnMut(&Path) -> Result<(), Error>,
+) -> Result<(), Error> {
+    for path in inputs {
+        f(path).map_err(|err| {
+            if inputs.len() > 1 {
+                Error::Input {
+                    path: path.clone(),
+                    err: Box::new(err),
+                }
+            } else {
+                err
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// Opens stdout or the `--output` file for the printed output. Stdout is only
+/// line-buffered, so would otherwise be written once per line.
+fn output_writer(config: &Config) -> io::Result<BufWriter<Box<dyn Write>>> {
+    Ok(BufWriter::new(match &config.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    }))
+}
+
+/// Reads the rows of the inputs for the modes which are not benchmarked, and
+/// returns the counts of lines of each kind. `header` is called with the
+/// header of each input, and `row` with the line, fields, and columns of each
+/// row which passes the filters, both with the `state` they share.
+fn for_each_row<T>(
+    inputs: &[PathBuf],
+    config: &Config,
+    state: &mut T,
+    mut header: impl FnMut(&mut T, &[u8]) -> io::Result<()>,
+    mut row: impl FnMut(&mut T, &[u8], &[&[u8]], &ColIndices) -> io::Result<()>,
+) -> Result<RowCounts, Error> {
+    let mut counts = RowCounts::default();
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let mut reader = LineReader::new(Input::open(path)?, config.buf_size);
+        let header_line = reader.read_header(config)?;
+        let (idx, header_len) = ColIndices::from_header(&header_line, config)?;
+        let idx = headers.check(idx)?;
+        header(state, &header_line)?;
+        let mut rows = RowFilter::new(header_line, idx, header_len);
+        let mut line_num = config.first_row_line() - 1;
+        while let Some(line) = reader.next_line()? {
+            line_num += 1;
+            if counts.rows() == config.limit {
+                break;
+            }
+            rows.filter_line(line, line_num, &mut counts, config, |cols, idx, _| {
+                Ok(row(state, line, cols, idx)?)
+            })?;
+        }
+        Ok(())
+    })?;
+    Ok(counts)
+}
+
+/// The header of an input, for counting its lines by kind and splitting the
+/// fields of its rows outside of the benchmarked strategies.
+struct RowFilter {
+    header: Vec<u8>,
+    idx: ColIndices,
+    header_len: usize,
+    /// The allocation for the fields of a row, kept empty between rows.
+    cols_empty: Vec<&'static [u8]>,
+}
+
+impl RowFilter {
+    fn new(header: Vec<u8>, idx: ColIndices, header_len: usize) -> Self {
+        let cols_empty = Vec::with_capacity(idx.useful_cols());
+        RowFilter {
+            header,
+            idx,
+            header_len,
+            cols_empty,
+        }
+    }
+
+    /// Counts a line after the header by its kind and, for a row which passes
+    /// the filters, calls `row` with its fields and the counts, after it is
+    /// counted as processed.
+    #[inline]
+    fn filter_line(
+        &mut self,
+        line: &[u8],
+        line_num: usize,
+        counts: &mut RowCounts,
+        config: &Config,
+        row: impl FnOnce(&[&[u8]], &ColIndices, &mut RowCounts) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if line.is_empty() {
+            counts.blank += 1;
+            return Ok(());
+        }
+        if config.is_ignored(line, &self.header) {
+            counts.ignored += 1;
+            return Ok(());
+        }
+        let useful_cols = self.idx.useful_cols();
+        let mut cols = mem::take(&mut self.cols_empty);
+        split_fields(&mut cols, line, useful_cols, config);
+        if !config
+            .check_width(line, cols.len(), useful_cols, self.header_len)
+            .map_err(|err| err.at_line(line_num))?
+        {
+            counts.malformed += 1;
+        } else if config
+            .matches_row(&cols, &self.idx, || line_num)
+            .map_err(|err| err.at_line(line_num))?
+        {
+            counts.processed += 1;
+            row(&cols, &self.idx, counts)?;
+        } else {
+            counts.skipped += 1;
+        }
+        self.cols_empty = cols.into_iter().take(0).map(|_| &[][..]).collect();
+        Ok(())
+    }
+}
+
+/// Writes the lines of the rows which pass the filters verbatim to stdout or
+/// the `--output` file, after the header of the first input, instead of
+/// aggregating them, and returns the counts of rows.
+pub fn filter(inputs: &[PathBuf], config: &Config) -> Result<RowCounts, Error> {
+    let config = &*sniff_inputs(inputs, config)?;
+    let mut w = output_writer(config)?;
+    let mut first = true;
+    let counts = for_each_row(
+        inputs,
+        config,
+        &mut w,
+        |w, header| {
+            if mem::take(&mut first) && !config.no_header {
+                w.write_all(header)?;
+                w.write_all(b"\n")?;
+            }
+            Ok(())
+        },
+        |w, line, _, _| {
+            w.write_all(line)?;
+            w.write_all(b"\n")?;
+            Ok(())
+        },
+    )?;
+    w.flush()?;
+    Ok(counts)
+}
+
+/// Writes the number of rows from each distinct `Source` to stdout or the
+/// `--output` file, most common first, instead of aggregating products, and
+/// returns the counts of rows. Rows from every source are tallied, but the
+/// other filters still apply.
+pub fn source_summary(inputs: &[PathBuf], config: &Config) -> Result<RowCounts, Error> {
+    let config = &Config {
+        source: None,
+        ..sniff_inputs(inputs, config)?.into_owned()
+    };
+    let mut sources = HashMap::<Box<[u8]>, usize>::new();
+    let counts = for_each_row(
+        inputs,
+        config,
+        &mut sources,
+        |_, _| Ok(()),
+        |sources, _, cols, idx| {
+            *sources.entry_ref(cols[idx.source]).or_default() += 1;
+            Ok(())
+        },
+    )?;
+
+    let mut sources: Vec<_> = sources.iter().collect();
+    sources.sort_unstable_by(|(source1, n1), (source2, n2)| {
+        n2.cmp(n1).then_with(|| source1.cmp(source2))
+    });
+    let sources: Vec<_> = sources
+        .into_iter()
+        .map(|(source, &n)| (source.escape_ascii().to_string(), n))
+        .collect();
+    let width = sources.iter().map(|(source, _)| source.len()).max();
+    let mut w = output_writer(config)?;
+    for (source, n) in &sources {
+        writeln!(w, "{source:<width$} {n}", width = width.unwrap())?;
+    }
+    w.flush()?;
+    Ok(counts)
+}
+
+/// Writes the number of lines after the header of the inputs and their
+/// shortest, longest, and mean length in bytes, without line endings, to
+/// stdout or the `--output` file, instead of aggregating products. Blank lines
+/// are counted apart and do not bound the lengths.
+pub fn line_stats(inputs: &[PathBuf], config: &Config) -> Result<(), Error> {
+    let mut lines = 0usize;
+    let mut blank = 0usize;
+    let mut total_len = 0u64;
+    // The length of each extreme and where it is, as an index into `inputs`
+    // and a line number.
+    let mut min: Option<(usize, usize, usize)> = None;
+    let mut max: Option<(usize, usize, usize)> = None;
+    let mut input = 0;
+    for_each_input(inputs, |path| {
+        let mut reader = LineReader::new(Input::open(path)?, config.buf_size);
+        // With `--no-header`, the header is returned again as the first row.
+        reader.read_header(config)?;
+        let mut line_num = config.first_row_line() - 1;
+        while let Some(line) = reader.next_line()? {
+            line_num += 1;
+            lines += 1;
+            if line.is_empty() {
+                blank += 1;
+                continue;
+            }
+            total_len += line.len() as u64;
+            let at = (line.len(), input, line_num);
+            if min.is_none_or(|(len, ..)| line.len() < len) {
+                min = Some(at);
+            }
+            if max.is_none_or(|(len, ..)| line.len() > len) {
+                max = Some(at);
+            }
+        }
+        input += 1;
+        Ok(())
+    })?;
+
+    let mut w = output_writer(config)?;
+    write!(w, "lines={lines} blank={blank}")?;
+    // Lines are numbered within each input, so are qualified by it when there
+    // are several.
+    let at = |input: usize, line_num: usize| {
+        if inputs.len() > 1 {
+            format!("{}:{line_num}", inputs[input].display())
+        } else {
+            format!("line {line_num}")
+        }
+    };
+    if let (Some((min_len, min_input, min_line)), Some((max_len, max_input, max_line))) = (min, max)
+    {
+        let mean = total_len as f64 / (lines - blank) as f64;
+        write!(
+            w,
+            " min={min_len} ({}) max={max_len} ({}) mean={mean:.2}",
+            at(min_input, min_line),
+            at(max_input, max_line),
+        )?;
+    }
+    writeln!(w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Prints the columns in the header of each input, marking those which are
+/// aggregated, without reading any rows, to diagnose unrecognized headers.
+pub fn inspect(inputs: &[PathBuf], config: &Config) -> Result<(), Error> {
+    for_each_input(inputs, |path| {
+        let mut header = Vec::new();
+        let mut reader = BufReader::new(Input::open(path)?);
+        skip_rows_buffered(&mut reader, config)?;
+        if reader.read_until(b'\n', &mut header)? == 0 {
+            return Err(HeaderError::Empty.into());
+        }
+        let header = trim_eol(&header);
+        let sniffed;
+        let config = if config.delimiter_auto {
+            sniffed = config.with_delimiter(sniff_delimiter(header)?);
+            &sniffed
+        } else {
+            config
+        };
+        let mut fields = Vec::new();
+        split_fields(&mut fields, strip_bom(header)?, usize::MAX, config);
+        let res = ColIndices::from_header(header, config);
+        let positions = match &res {
+            Ok((idx, _)) => idx.positions().collect(),
+            Err(_) => Vec::new(),
+        };
+        if inputs.len() > 1 {
+            println!("{}:", path.display());
+        }
+        for (i, &field) in fields.iter().enumerate() {
+            // Columns which are not needed with these options are located at
+            // index 0, so also match them by name, which, with `--no-header`,
+            // is given by `--columns`.
+            let named = |name: &str| {
+                if config.no_header {
+                    config.columns.iter().any(|(n, j)| *j == i && **n == *name)
+                } else {
+                    config.is_column(config.column_name(field), name)
+                }
+            };
+            let used = positions.iter().any(|&(name, j)| j == i && named(name));
+            let mark = if used { '*' } else { ' ' };
+            println!("{:4} {mark} {}", i + 1, field.escape_ascii());
+        }
+        res.map(|_| ()).map_err(Error::from)
+    })
+}
+
+/// Runs every strategy on the same inputs and checks that they all aggregate
+/// identical products. Strategies which do not support the inputs or options
+/// are skipped.
+pub fn verify(inputs: &[PathBuf], config: &Config) -> Result<(), Error> {
+    let mut expected = Expected::default();
+    for &strategy in STRATEGIES {
+        let mut sink = Collect::default();
+        match run_strategy(strategy, inputs, config, &mut sink) {
+            Ok(()) => {}
+            Err(Error::Unsupported(reason)) => {
+                println!("{strategy}: skipped ({reason})");
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+        expected.check(strategy, sink.products, config)?;
+        println!("{strategy}: ok");
+    }
+    Ok(())
+}
+
+/// The products aggregated by the first strategy to run, against which the
+/// others are checked.
+#[derive(Default)]
+pub struct Expected {
+    strategy: Option<&'static str>,
+    products: Vec<(Vec<u8>, ProductData)>,
+}
+
+impl Expected {
+    /// Checks that a strategy aggregated the same products as the first, or
+    /// takes its products as the expectation when it is the first.
+    pub fn check(
+        &mut self,
+        strategy: &'static str,
+        mut actual: Vec<(Vec<u8>, ProductData)>,
+        config: &Config,
+    ) -> Result<(), Error> {
+        actual.sort_unstable_by(|(prod1, _), (prod2, _)| prod1.cmp(prod2));
+        let Some(expected_strategy) = self.strategy else {
+            self.strategy = Some(strategy);
+            self.products = actual;
+            return Ok(());
+        };
+        if let Some((prod, expected_data, actual_data)) = first_difference(&self.products, &actual)
+        {
+            let mut stderr = io::stderr().lock();
+            for (strategy, data) in [(expected_strategy, expected_data), (strategy, actual_data)] {
+                write!(stderr, "{strategy}: ")?;
+                match data {
+                    Some(data) => {
+                        let (prod, side) = split_group_key(&prod, config);
+                        data.fmt(
+                            &mut stderr,
+                            prod,
+                            side,
+                            &config.output_fields(),
+                            config.precision,
+                        )?
+                    }
+                    None => writeln!(stderr, "missing {}", String::from_utf8_lossy(&prod))?,
+                }
+            }
+            return Err(Error::Mismatch {
+                expected: expected_strategy,
+                actual: strategy,
+                prod,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Finds the first product, in sorted order, on which two sorted collections
+/// of products disagree.
+fn first_difference<'a>(
+    expected: &'a [(Vec<u8>, ProductData)],
+    actual: &'a [(Vec<u8>, ProductData)],
+) -> Option<(Vec<u8>, Option<&'a ProductData>, Option<&'a ProductData>)> {
+    let (mut i, mut j) = (0, 0);
+    loop {
+        match (expected.get(i), actual.get(j)) {
+            (None, None) => return None,
+            (Some((prod, data)), None) => return Some((prod.clone(), Some(data), None)),
+            (None, Some((prod, data))) => return Some((prod.clone(), None, Some(data))),
+            (Some((prod1, data1)), Some((prod2, data2))) => match prod1.cmp(prod2) {
+                Ordering::Less => return Some((prod1.clone(), Some(data1), None)),
+                Ordering::Greater => return Some((prod2.clone(), None, Some(data2))),
+                Ordering::Equal if !data1.approx_eq(data2) => {
+                    return Some((prod1.clone(), Some(data1), Some(data2)));
+                }
+                Ordering::Equal => {
+                    i += 1;
+                    j += 1;
+                }
+            },
+        }
+    }
+}
+
+/// Receives the aggregated products at the end of a strategy, or with
+/// `--follow`, each time they are re-printed.
+pub trait Sink {
+    fn finish<'a>(
+        &mut self,
+        iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+        counts: &RowCounts,
+        config: &Config,
+    ) -> io::Result<()>;
+}
+
+/// Periodically reports the lines and bytes processed to stderr with
+/// `--progress`.
+struct Progress {
+    lines: usize,
+    bytes: u64,
+    /// The line count at which to report next, or `usize::MAX` when disabled.
+    next_report: usize,
+    /// The length of the input, when it is known up front.
+    total: Option<u64>,
+}
+
+/// The number of lines between progress reports.
+const PROGRESS_INTERVAL: usize = 1_000_000;
+
+impl Progress {
+    fn new(config: &Config, total: Option<u64>) -> Self {
+        Progress {
+            lines: 0,
+            bytes: 0,
+            next_report: if config.progress {
+                PROGRESS_INTERVAL
+            } else {
+                usize::MAX
+            },
+            total,
+        }
+    }
+
+    /// Records a line of `len` bytes, including its terminator.
+    #[inline]
+    fn tick(&mut self, len: usize) {
+        self.lines += 1;
+        self.bytes += len as u64;
+        if self.lines == self.next_report {
+            self.next_report += PROGRESS_INTERVAL;
+            self.report();
+        }
+    }
+
+    /// Overwrites the progress line with the current counts.
+    #[cold]
+    fn report(&self) {
+        let mut stderr = io::stderr().lock();
+        let _ = match self.total {
+            Some(total) => write!(
+                stderr,
+                "\r{} lines, {}/{} bytes ({:.1}%)",
+                self.lines,
+                self.bytes,
+                total,
+                self.bytes as f64 * 100.0 / total.max(1) as f64,
+            ),
+            None => write!(stderr, "\r{} lines, {} bytes", self.lines, self.bytes),
+        };
+    }
+
+    /// Reports the final counts and ends the progress line.
+    fn finish(&self) {
+        if self.next_report != usize::MAX {
+            self.report();
+            eprintln!();
+        }
+    }
+}
+
+/// The number of `--check-invariants` violations to report the lines of.
+const REPORTED_VIOLATIONS: usize = 5;
+
+/// The number of lines of each kind seen by a strategy.
+#[derive(Default)]
+pub struct RowCounts {
+    /// Rows which passed the filters and were aggregated.
+    pub processed: usize,
+    /// Rows which were excluded by the filters.
+    pub skipped: usize,
+    /// Empty lines.
+    pub blank: usize,
+    /// Rows with too few fields to aggregate, which are skipped unless
+    /// `--strict` is given.
+    pub malformed: usize,
+    /// Lines starting with the `--comment` byte and repeats of the header, as
+    /// in concatenated files.
+    pub ignored: usize,
+    /// Aggregated rows with quantities out of order, with
+    /// `--check-invariants`.
+    pub violations: usize,
+    /// The 1-based line numbers of the first few violations.
+    pub first_violations: Vec<usize>,
+}
+
+impl RowCounts {
+    /// The number of rows consumed, excluding blank and ignored lines.
+    #[inline]
+    pub fn rows(&self) -> usize {
+        self.processed + self.skipped + self.malformed
+    }
+
+    #[cold]
+    fn add_violation(&mut self, line: usize) {
+        self.violations += 1;
+        if self.first_violations.len() < REPORTED_VIOLATIONS {
+            self.first_violations.push(line);
+        }
+    }
+
+    #[inline]
+    fn merge(&mut self, other: &RowCounts) {
+        self.processed += other.processed;
+        self.skipped += other.skipped;
+        self.blank += other.blank;
+        self.malformed += other.malformed;
+        self.ignored += other.ignored;
+        self.violations += other.violations;
+        self.first_violations
+            .extend_from_slice(&other.first_violations);
+        self.first_violations.truncate(REPORTED_VIOLATIONS);
+    }
+}
+
+impl Display for RowCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "processed={} skipped={} blank={} malformed={} ignored={}",
+            self.processed, self.skipped, self.blank, self.malformed, self.ignored,
+        )
+    }
+}
+
+/// Prints the products to stdout or the `--output` file, unless `--quiet` is
+/// given.
+pub struct Print;
+
+/// Collects owned copies of the products of the last run.
+#[derive(Default)]
+pub struct Collect {
+    pub products: Vec<(Vec<u8>, ProductData)>,
+}
+
+impl Sink for Print {
+    #[inline]
+    fn finish<'a>(
+        &mut self,
+        iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+        counts: &RowCounts,
+        config: &Config,
+    ) -> io::Result<()> {
+        if config.check_invariants {
+            eprint!("Invariant violations: {}", counts.violations);
+            if !counts.first_violations.is_empty() {
+                let lines = counts.first_violations.iter().map(usize::to_string);
+                eprint!(" (first on lines {})", lines.collect::<Vec<_>>().join(", "));
+            }
+            eprintln!();
+        }
+        if config.quiet {
+            return Ok(());
+        }
+        let start = Instant::now();
+        if config.split_output.is_some() {
+            split_products(iter, counts, config)?;
+        } else {
+            let mut w = output_writer(config)?;
+            print_products(&mut w, iter, counts, config)?;
+            w.flush()?;
+        }
+        phases::add_output(start.elapsed());
+        Ok(())
+    }
+}
+
+impl Sink for Collect {
+    fn finish<'a>(
+        &mut self,
+        iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+        _counts: &RowCounts,
+        _config: &Config,
+    ) -> io::Result<()> {
+        self.products.clear();
+        self.products
+            .extend(iter.map(|(prod, data)| (prod.to_vec(), data.clone())));
+        Ok(())
+    }
+}
+
+/// Options from the command line.
+#[derive(Clone)]
+pub struct Config {
+    /// The byte separating fields within a line.
+    pub delimiter: u8,
+    /// Whether to detect `delimiter` from the header of the first input
+    /// instead, as the most frequent of `,`, tab, `|`, and `;`.
+    pub delimiter_auto: bool,
+    /// The order in which products are printed.
+    pub sort: SortOrder,
+    /// The format in which products are printed.
+    pub format: Format,
+    /// Whether fields may be quoted as in RFC 4180.
+    pub rfc4180: bool,
+    /// The number of times to run the strategy, including a warmup.
+    pub runs: usize,
+    /// Whether to aggregate the products without printing them.
+    pub quiet: bool,
+    /// The `Source` value of rows to aggregate, or `None` for all rows.
+    pub source: Option<Box<[u8]>>,
+    /// The `group_by` value of rows to aggregate, or `None` for all rows.
+    /// With `normalize_keys`, it is compared ignoring ASCII case.
+    pub prod: Option<Box<[u8]>>,
+    /// Whether to uppercase the ASCII letters of `group_by` values, so that
+    /// symbols which differ only by case are aggregated together. Strategies
+    /// which borrow keys from the input fall back to copying them, or do not
+    /// support it.
+    pub normalize_keys: bool,
+    /// Whether to report allocated memory.
+    pub mem: bool,
+    /// Whether to report the time spent in each phase of the last run.
+    pub phases: bool,
+    /// The most data rows to process, or `usize::MAX` for all of them.
+    pub limit: usize,
+    /// The capacity of the `LineReader` buffer.
+    pub buf_size: usize,
+    /// The file to print products to, or `None` for stdout.
+    pub output: Option<PathBuf>,
+    /// The earliest `Time` of rows to aggregate, inclusive, in seconds since
+    /// midnight.
+    pub after: Option<u32>,
+    /// The latest `Time` of rows to aggregate, exclusive, in seconds since
+    /// midnight.
+    pub before: Option<u32>,
+    /// The number of highest-ranked products to print, or `None` for all.
+    pub top: Option<usize>,
+    /// The metric by which `--top` ranks products.
+    pub top_by: TopBy,
+    /// Whether to periodically report progress to stderr.
+    pub progress: bool,
+    /// The number of threads for the parallel strategies, or `None` for one
+    /// per logical core.
+    pub threads: Option<usize>,
+    /// The column to take row quantities from, or `None` to combine OrdQty,
+    /// WrkQty, and ExcQty by `qty_reduce`.
+    pub qty_col: Option<&'static str>,
+    /// The column to group rows by, instead of `Prod`.
+    pub group_by: &'static str,
+    /// Whether rows are also grouped by their `B/S` value, under a key of the
+    /// `group_by` value, a NUL, and the `B/S` value. Only the strategies
+    /// which copy keys can build such a key.
+    pub group_by_side: bool,
+    /// Whether to compute the volume-weighted average price of each product
+    /// from the `Price` column.
+    pub with_price: bool,
+    /// Whether a row with a different number of fields than the header is an
+    /// error, rather than skipped when it is too short to aggregate.
+    pub strict: bool,
+    /// Whether to print the standard deviation of row quantities.
+    pub stats: bool,
+    /// Whether to skip rows with an empty `Prod`, instead of aggregating them
+    /// as `<empty>`.
+    pub exclude_blank_products: bool,
+    /// The `B/S` value of buy rows.
+    pub buy_token: Box<[u8]>,
+    /// The `B/S` value of sell rows.
+    pub sell_token: Box<[u8]>,
+    /// Lines starting with this byte are skipped as comments.
+    pub comment: Option<u8>,
+    /// Only count the rows which pass the filters, without aggregating them
+    /// by product, to time reading and splitting alone.
+    pub count_only: bool,
+    /// The interval at which to poll the input for appended rows and
+    /// re-print the products, or `None` to stop at the end of the input.
+    pub follow: Option<Duration>,
+    /// Whether to clear the products and counts each time they are printed
+    /// with `follow`, so that each is of the rows since the last.
+    pub follow_reset: bool,
+    /// How rows with an empty quantity field are aggregated.
+    pub null_qty: NullQty,
+    /// The largest plausible quantity of a row, above which it is reported
+    /// as suspicious and handled by `max_qty_policy`.
+    pub max_qty: Option<u32>,
+    /// How rows with a quantity above `max_qty` are aggregated.
+    pub max_qty_policy: MaxQtyPolicy,
+    /// Whether to count rows by the power of ten of their quantities.
+    pub histogram: bool,
+    /// Whether to escape control characters and invalid UTF-8 in printed
+    /// product keys.
+    pub safe_keys: bool,
+    /// Whether to count rows with quantities out of order.
+    pub check_invariants: bool,
+    /// The number of products to allocate for up front, so that maps are not
+    /// rehashed as they grow.
+    pub products_hint: usize,
+    /// Whether the first line of each input is a row rather than a header, so
+    /// that columns are located by `columns` instead.
+    pub no_header: bool,
+    /// Whether column names in the header are matched ignoring ASCII case.
+    pub case_insensitive_header: bool,
+    /// The 0-based index of each named column, for `--no-header`.
+    pub columns: Vec<(Box<str>, usize)>,
+    /// The header names of renamed columns, as pairs of a fixed column name,
+    /// such as `OrdQty`, and the name it has in the header, from `--schema`.
+    pub header_names: Vec<(&'static str, Box<str>)>,
+    /// The number of lines of metadata to discard before the header of each
+    /// input.
+    pub skip_rows: usize,
+    /// The format in which the timings of runs are printed.
+    pub bench_format: BenchFormat,
+    /// How OrdQty, WrkQty, and ExcQty combine into the quantity of a row,
+    /// unless `qty_col` is given.
+    pub qty_reduce: QtyReduce,
+    /// The directory to print each product to a file of its own in, instead
+    /// of printing them together.
+    pub split_output: Option<PathBuf>,
+    /// The decimal places of every float metric, or `None` for the default
+    /// of each.
+    pub precision: Option<usize>,
+    /// Whether to print the shortest, longest, and mean line lengths, instead
+    /// of aggregating products.
+    pub line_stats: bool,
+    /// Whether to print the number of rows from each `Source`, instead of
+    /// aggregating products.
+    pub source_summary: bool,
+    /// Whether to record and print the lines of the first and last row of
+    /// each product.
+    pub trace: bool,
+    /// Whether a row with a product or `B/S` which is not valid UTF-8 is an
+    /// error.
+    pub validate_utf8: bool,
+    /// Whether to read every input once before timing, so that runs start
+    /// with it in the page cache.
+    pub warm_cache: bool,
+    /// The metrics to print for each product, in order, or empty for those
+    /// of the format and the other options.
+    pub fields: Vec<OutputField>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Hash map iteration order, which is fastest.
+    None,
+    /// Lexicographically by product symbol.
+    Symbol,
+    /// Descending by row count, then by product symbol.
+    Count,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TopBy {
+    /// The number of rows.
+    Count,
+    /// The total row quantity.
+    Qty,
+}
+
+/// How rows with an empty quantity field are aggregated.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NullQty {
+    /// Fail, as for any other invalid quantity.
+    Error,
+    /// Aggregate the quantity as 0.
+    Zero,
+    /// Skip the row, as if excluded by a filter.
+    Skip,
+}
+
+/// How rows with a quantity above `--max-qty` are aggregated.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MaxQtyPolicy {
+    /// Skip the row, as if excluded by a filter.
+    Skip,
+    /// Aggregate the quantity as `--max-qty`.
+    Clamp,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Space-separated text with one product per line.
+    Text,
+    /// A JSON array of objects.
+    Json,
+    /// Comma-separated values with a header row.
+    Csv,
+    /// Length-prefixed binary records, as read by `read_bin_product`.
+    Bin,
+}
+
+/// A metric printed for each product, selected by `--fields`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputField {
+    Count,
+    Buys,
+    Sells,
+    AvgQty,
+    MinQty,
+    MaxQty,
+    BuyQty,
+    SellQty,
+    ExcTotal,
+    StddevQty,
+    BuyPct,
+    Rate,
+    Vwap,
+    QtyHist,
+    FirstLine,
+    LastLine,
+}
+
+impl OutputField {
+    /// Every field, in the order they are printed by default.
+    pub const ALL: [OutputField; 16] = [
+        OutputField::Count,
+        OutputField::Buys,
+        OutputField::Sells,
+        OutputField::AvgQty,
+        OutputField::MinQty,
+        OutputField::MaxQty,
+        OutputField::BuyQty,
+        OutputField::SellQty,
+        OutputField::ExcTotal,
+        OutputField::StddevQty,
+        OutputField::BuyPct,
+        OutputField::Rate,
+        OutputField::Vwap,
+        OutputField::QtyHist,
+        OutputField::FirstLine,
+        OutputField::LastLine,
+    ];
+
+    /// The name of the field, as in `--fields` and JSON output.
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputField::Count => "count",
+            OutputField::Buys => "buys",
+            OutputField::Sells => "sells",
+            OutputField::AvgQty => "avg_qty",
+            OutputField::MinQty => "min_qty",
+            OutputField::MaxQty => "max_qty",
+            OutputField::BuyQty => "buy_qty",
+            OutputField::SellQty => "sell_qty",
+            OutputField::ExcTotal => "exc_total",
+            OutputField::StddevQty => "stddev_qty",
+            OutputField::BuyPct => "buy_pct",
+            OutputField::Rate => "rate",
+            OutputField::Vwap => "vwap",
+            OutputField::QtyHist => "qty_hist",
+            OutputField::FirstLine => "first_line",
+            OutputField::LastLine => "last_line",
+        }
+    }
+
+    /// Looks up a field by its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        OutputField::ALL
+            .into_iter()
+            .find(|field| field.name() == name)
+    }
+
+    /// The name of the field in a CSV header, or the prefix of the name of
+    /// each bucket for `qty_hist`.
+    fn csv_name(self) -> &'static str {
+        match self {
+            OutputField::Count => "Count",
+            OutputField::Buys => "Buys",
+            OutputField::Sells => "Sells",
+            OutputField::AvgQty => "AvgQty",
+            OutputField::MinQty => "MinQty",
+            OutputField::MaxQty => "MaxQty",
+            OutputField::BuyQty => "BuyQty",
+            OutputField::SellQty => "SellQty",
+            OutputField::ExcTotal => "ExcTotal",
+            OutputField::StddevQty => "StdDevQty",
+            OutputField::BuyPct => "BuyPct",
+            OutputField::Rate => "Rate",
+            OutputField::Vwap => "VWAP",
+            OutputField::QtyHist => "Qty",
+            OutputField::FirstLine => "FirstLine",
+            OutputField::LastLine => "LastLine",
+        }
+    }
+}
+
+/// How OrdQty, WrkQty, and ExcQty combine into the quantity of a row.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum QtyReduce {
+    /// The largest of the three.
+    Max,
+    /// The sum of the three, saturating at `u32::MAX`.
+    Sum,
+    /// The first which is nonzero, in the order OrdQty, WrkQty, ExcQty.
+    First,
+    /// The smallest of the three.
+    Min,
+}
+
+impl QtyReduce {
+    /// Combines the quantities of a row.
+    #[inline]
+    pub fn reduce(self, ordqty: u32, wrkqty: u32, excqty: u32) -> u32 {
+        match self {
+            QtyReduce::Max => ordqty.max(wrkqty.max(excqty)),
+            QtyReduce::Sum => ordqty.saturating_add(wrkqty).saturating_add(excqty),
+            QtyReduce::First => [ordqty, wrkqty, excqty]
+                .into_iter()
+                .find(|&qty| qty != 0)
+                .unwrap_or(0),
+            QtyReduce::Min => ordqty.min(wrkqty.min(excqty)),
+        }
+    }
+}
+
+/// The format in which the timings of runs are printed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BenchFormat {
+    /// Lines summarizing the runs for reading.
+    Text,
+    /// A JSON object per line with the time of each run, for scripts.
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            delimiter: b',',
+            delimiter_auto: false,
+            sort: SortOrder::None,
+            format: Format::Text,
+            rfc4180: false,
+            runs: 1,
+            quiet: false,
+            source: Some(b"ToClnt"[..].into()),
+            prod: None,
+            normalize_keys: false,
+            mem: false,
+            phases: false,
+            limit: usize::MAX,
+            buf_size: BUF_CAP,
+            output: None,
+            after: None,
+            before: None,
+            top: None,
+            top_by: TopBy::Count,
+            progress: false,
+            threads: None,
+            qty_col: None,
+            group_by: "Prod",
+            group_by_side: false,
+            with_price: false,
+            strict: false,
+            stats: false,
+            exclude_blank_products: false,
+            buy_token: b"Buy"[..].into(),
+            sell_token: b"Sell"[..].into(),
+            comment: None,
+            count_only: false,
+            follow: None,
+            follow_reset: false,
+            null_qty: NullQty::Error,
+            max_qty: None,
+            max_qty_policy: MaxQtyPolicy::Skip,
+            histogram: false,
+            safe_keys: false,
+            check_invariants: false,
+            products_hint: 0,
+            no_header: false,
+            case_insensitive_header: false,
+            columns: Vec::new(),
+            header_names: Vec::new(),
+            skip_rows: 0,
+            bench_format: BenchFormat::Text,
+            qty_reduce: QtyReduce::Max,
+            split_output: None,
+            precision: None,
+            line_stats: false,
+            source_summary: false,
+            trace: false,
+            validate_utf8: false,
+            warm_cache: false,
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// This config with a delimiter detected by `--delimiter-auto`.
+    fn with_delimiter(&self, delimiter: u8) -> Config {
+        Config {
+            delimiter,
+            delimiter_auto: false,
+            ..self.clone()
+        }
+    }
+
+    /// The metrics to print for each product: those of `--fields`, or by
+    /// default, those of the format and of `--stats`, `--with-price`,
+    /// `--histogram`, and `--trace`. CSV omits the quantity totals by
+    /// default.
+    fn output_fields(&self) -> Cow<'_, [OutputField]> {
+        use OutputField::*;
+        if !self.fields.is_empty() {
+            return Cow::Borrowed(&self.fields);
+        }
+        let mut fields = match self.format {
+            Format::Text | Format::Json => vec![
+                Count, Buys, Sells, AvgQty, MinQty, MaxQty, BuyQty, SellQty, ExcTotal,
+            ],
+            Format::Csv => vec![Count, Buys, Sells, AvgQty],
+            // The records of `--format bin` have fixed fields.
+            Format::Bin => Vec::new(),
+        };
+        if self.stats {
+            fields.extend([StddevQty, BuyPct, Rate]);
+        }
+        if self.with_price {
+            fields.push(Vwap);
+        }
+        if self.histogram {
+            fields.push(QtyHist);
+        }
+        if self.trace {
+            fields.extend([FirstLine, LastLine]);
+        }
+        Cow::Owned(fields)
+    }
+
+    /// Sets the column to group by from `--group-by`, which also groups by
+    /// `B/S` with a `,bs` suffix. Built-in columns may be given by their
+    /// lowercase names, as in `--columns`.
+    pub fn set_group_by(&mut self, value: &str) {
+        let value = match value.strip_suffix(",bs") {
+            Some(value) => {
+                self.group_by_side = true;
+                value
+            }
+            None => value,
+        };
+        // Leaked like `--qty-col`.
+        self.group_by = builtin_column(value).unwrap_or_else(|| value.to_owned().leak());
+    }
+
+    /// Whether a header field names a column, ignoring ASCII case with
+    /// `--case-insensitive-header`.
+    #[inline]
+    fn is_column(&self, field: &[u8], name: &str) -> bool {
+        if self.case_insensitive_header {
+            field.eq_ignore_ascii_case(name.as_bytes())
+        } else {
+            field == name.as_bytes()
+        }
+    }
+
+    /// The fixed column name which a header field stands for: the column it
+    /// renames, or with `--case-insensitive-header`, the column it matches
+    /// ignoring case. Other fields are returned as is.
+    #[inline]
+    fn column_name<'a>(&self, field: &'a [u8]) -> &'a [u8] {
+        if let Some((name, _)) = self
+            .header_names
+            .iter()
+            .find(|(_, header)| self.is_column(field, header))
+        {
+            return name.as_bytes();
+        }
+        if self.case_insensitive_header {
+            COLUMN_NAMES
+                .iter()
+                .find(|name| field.eq_ignore_ascii_case(name.as_bytes()))
+                .map_or(field, |name| name.as_bytes())
+        } else {
+            field
+        }
+    }
+
+    /// Whether a line is a `--comment` or repeats the header, so is not a
+    /// row. A header row never holds valid quantities, so repeated headers are
+    /// always skipped, unless there is no header and the first line is a row.
+    #[inline]
+    fn is_ignored(&self, line: &[u8], header: &[u8]) -> bool {
+        (!self.no_header && line == header)
+            || self.comment.is_some_and(|c| line.first() == Some(&c))
+    }
+
+    #[inline]
+    fn matches_source(&self, source: &[u8]) -> bool {
+        match &self.source {
+            Some(expected) => source == &**expected,
+            None => true,
+        }
+    }
+
+    /// Whether a row passes the `--source`, `--prod`, and
+    /// `--exclude-blank-products` filters, has its quantities with
+    /// `--null-qty-policy skip`, has a quantity within `--max-qty` with
+    /// `--max-qty-policy skip`, and is within the `--after` and `--before`
+    /// window. With `--validate-utf8`, a product or `B/S` which is not valid
+    /// UTF-8 is an error. A quantity above `--max-qty` is reported to stderr
+    /// with the line of its row.
+    #[inline]
+    fn matches_row(
+        &self,
+        cols: &[&[u8]],
+        idx: &ColIndices,
+        line_num: impl Fn() -> usize,
+    ) -> Result<bool, FieldError> {
+        self.matches_fields(|i| cols[i], idx, line_num)
+    }
+
+    /// Whether a row, of which `fields` fields were split up to
+    /// `useful_cols`, has the fields to aggregate. With `--strict`, every
+    /// field is counted and must match the `header_len` fields of the header.
+    /// A row has one more field than it has delimiters, so every strategy
+    /// counts a trailing delimiter as ending an empty field, whether it splits
+    /// fields or records the offsets of delimiters.
+    ///
+    /// Rows too short to index every column are never aggregated, even with
+    /// `--strict`, where, with `--no-header`, the first row which sets the
+    /// width may itself be too short for `--columns`.
+    #[inline]
+    fn check_width(
+        &self,
+        line: &[u8],
+        fields: usize,
+        useful_cols: usize,
+        header_len: usize,
+    ) -> Result<bool, FieldError> {
+        if !self.strict {
+            return Ok(fields >= useful_cols);
+        }
+        match self.count_fields(line) {
+            actual if actual == header_len => Ok(fields >= useful_cols),
+            actual => Err(FieldError::Width {
+                expected: header_len,
+                actual,
+            }),
+        }
+    }
+
+    /// Counts the fields of a line, which has one more than it has delimiters.
+    #[inline]
+    fn count_fields(&self, line: &[u8]) -> usize {
+        let delimiters = if self.rfc4180 {
+            QuotedDelimiters::new(line, self.delimiter).count()
+        } else {
+            memchr_iter(self.delimiter, line).count()
+        };
+        delimiters + 1
+    }
+
+    /// The line number of the first row of each input.
+    #[inline]
+    fn first_row_line(&self) -> usize {
+        let header_lines = if self.no_header { 0 } else { 1 };
+        self.skip_rows + header_lines + 1
+    }
+
+    /// A header for `--no-header`, which names the `--columns` at their
+    /// indices and leaves the other fields empty.
+    fn positional_header(&self) -> Vec<u8> {
+        let len = self.columns.iter().map(|&(_, i)| i + 1).max().unwrap_or(0);
+        let mut names = vec![&b""[..]; len];
+        for (name, i) in &self.columns {
+            names[*i] = name.as_bytes();
+        }
+        names.join(&self.delimiter)
+    }
+
+    /// Like `matches_row`, but gets the fields of a row by index from `col`.
+    #[inline]
+    fn matches_fields<'a>(
+        &self,
+        col: impl Fn(usize) -> &'a [u8],
+        idx: &ColIndices,
+        line_num: impl Fn() -> usize,
+    ) -> Result<bool, FieldError> {
+        if self.validate_utf8 {
+            validate_utf8(col(idx.prod), idx.group_by)?;
+            validate_utf8(col(idx.bs), "B/S")?;
+        }
+        if !self.matches_source(col(idx.source)) {
+            return Ok(false);
+        }
+        if let Some(prod) = &self.prod {
+            let matches = if self.normalize_keys {
+                col(idx.prod).eq_ignore_ascii_case(prod)
+            } else {
+                col(idx.prod) == &**prod
+            };
+            if !matches {
+                return Ok(false);
+            }
+        }
+        if self.exclude_blank_products && col(idx.prod).is_empty() {
+            return Ok(false);
+        }
+        if self.null_qty == NullQty::Skip {
+            let empty = |i| col(i).is_empty();
+            let null = match idx.qty {
+                Some((i, _)) => empty(i) || empty(idx.excqty),
+                None => empty(idx.ordqty) || empty(idx.wrkqty) || empty(idx.excqty),
+            };
+            if null {
+                return Ok(false);
+            }
+        }
+        if let Some(max) = self.max_qty {
+            let qty = row_qty(&col, idx, self)?;
+            if qty > max {
+                let action = match self.max_qty_policy {
+                    MaxQtyPolicy::Skip => "skipped",
+                    MaxQtyPolicy::Clamp => "clamped",
+                };
+                eprintln!(
+                    "line {}: suspicious quantity {qty} exceeds --max-qty {max}, {action}",
+                    line_num(),
+                );
+                if self.max_qty_policy == MaxQtyPolicy::Skip {
+                    return Ok(false);
+                }
+            }
+        }
+        let filter_time = self.after.is_some() || self.before.is_some();
+        let Some(time_idx) = idx.time.filter(|_| filter_time) else {
+            return Ok(true);
+        };
+        let time = col(time_idx);
+        let time = parse_time(time).ok_or_else(|| FieldError::new("Time", time))?;
+        Ok(self.after.is_none_or(|after| time >= after)
+            && self.before.is_none_or(|before| time < before))
+    }
+}
+
+/// The header name of a built-in column, by its lowercase name in `--columns`
+/// and `--schema`.
+pub fn builtin_column(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "source" => "Source",
+        "bs" => "B/S",
+        "prod" => "Prod",
+        "ordqty" => "OrdQty",
+        "wrkqty" => "WrkQty",
+        "excqty" => "ExcQty",
+        "time" => "Time",
+        "price" => "Price",
+        _ => return None,
+    })
+}
+
+/// Loads a `--schema` file into the config.
+///
+/// It is a subset of TOML with one `key = value` pair per line, where values
+/// are strings, integers, or booleans, and `#` starts a comment. Each key is
+/// given at most once. Columns, by their names in `--columns`, are given as a
+/// header name or, with `no_header = true`, as a 0-based index. `prod` names
+/// the `--group-by` column, `qty` the `--qty-col` column, and `qty_index` is
+/// its index. The other keys are `buy_token`, `sell_token`, `source_filter`,
+/// `delimiter`, and `no_header`, which are set like their options.
+pub fn load_schema(path: &Path, config: &mut Config) -> Result<(), Error> {
+    let in_file = |err| Error::Input {
+        path: path.to_owned(),
+        err: Box::new(err),
+    };
+    let text = fs::read_to_string(path).map_err(|err| in_file(err.into()))?;
+    parse_schema(&text, config).map_err(|(line, err)| in_file(Error::Schema { line, err }))
+}
+
+/// Parses the text of a `--schema` file into the config, or returns the
+/// first error with its 1-based line.
+fn parse_schema(text: &str, config: &mut Config) -> Result<(), (usize, SchemaError)> {
+    let mut keys = Vec::new();
+    // Indices are named by the columns they locate, which may be renamed on a
+    // later line, so are resolved after every line is read.
+    let mut indices = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_num = i + 1;
+        let at = |err| (line_num, err);
+        let Some((key, value)) = parse_schema_line(line).map_err(at)? else {
+            continue;
+        };
+        if keys.contains(&key) {
+            return Err(at(SchemaError::DuplicateKey(key.into())));
+        }
+        keys.push(key);
+        let invalid = || at(SchemaError::InvalidValue(key.into()));
+        match (key, value) {
+            ("prod", SchemaValue::Str(name)) => config.group_by = name.leak(),
+            ("qty", SchemaValue::Str(name)) => config.qty_col = Some(name.leak()),
+            (key, SchemaValue::Str(header)) if builtin_column(key).is_some() => {
+                let name = builtin_column(key).unwrap();
+                config.header_names.retain(|&(n, _)| n != name);
+                config.header_names.push((name, header.into()));
+            }
+            ("qty_index", SchemaValue::Int(index)) => indices.push((line_num, key, index)),
+            (key, SchemaValue::Int(index)) if builtin_column(key).is_some() => {
+                indices.push((line_num, key, index));
+            }
+            ("buy_token", SchemaValue::Str(token)) => config.buy_token = token.into_bytes().into(),
+            ("sell_token", SchemaValue::Str(token)) => {
+                config.sell_token = token.into_bytes().into();
+            }
+            ("source_filter", SchemaValue::Str(source)) => {
+                config.source = (source != "all").then(|| source.into_bytes().into());
+            }
+            ("delimiter", SchemaValue::Str(delimiter)) => match *delimiter.as_bytes() {
+                [b] if b.is_ascii() => config.delimiter = b,
+                _ => return Err(invalid()),
+            },
+            ("no_header", SchemaValue::Bool(no_header)) => config.no_header = no_header,
+            (
+                "qty" | "qty_index" | "buy_token" | "sell_token" | "source_filter" | "delimiter"
+                | "no_header",
+                _,
+            ) => return Err(invalid()),
+            (key, _) if builtin_column(key).is_some() => return Err(invalid()),
+            (key, _) => return Err(at(SchemaError::UnknownKey(key.into()))),
+        }
+    }
+    if indices.is_empty() {
+        return Ok(());
+    }
+    let mut columns = Vec::with_capacity(indices.len());
+    for (line_num, key, index) in indices {
+        if !config.no_header {
+            return Err((line_num, SchemaError::IndexWithHeader(key.into())));
+        }
+        let name = match key {
+            "prod" => config.group_by,
+            "qty_index" => config.qty_col.ok_or((line_num, SchemaError::UnnamedQty))?,
+            key => builtin_column(key).unwrap(),
+        };
+        columns.push((name.into(), index));
+    }
+    config.columns = columns;
+    Ok(())
+}
+
+/// A value in a `--schema` file.
+enum SchemaValue {
+    Str(String),
+    Int(usize),
+    Bool(bool),
+}
+
+/// Parses a line of a `--schema` file as a key and value, or `None` for a
+/// blank or comment line.
+fn parse_schema_line(line: &str) -> Result<Option<(&str, SchemaValue)>, SchemaError> {
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (key, rest) = line
+        .split_once('=')
+        .ok_or(SchemaError::Syntax("expected `key = value`"))?;
+    let key = key.trim_end();
+    let rest = rest.trim_start();
+    let (value, rest) = if let Some(s) = rest.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = s.char_indices();
+        loop {
+            match chars.next() {
+                Some((i, '"')) => break (SchemaValue::Str(value), &s[i + 1..]),
+                Some((_, '\\')) => value.push(match chars.next() {
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'n')) => '\n',
+                    _ => return Err(SchemaError::Syntax("unknown escape in string")),
+                }),
+                Some((_, ch)) => value.push(ch),
+                None => return Err(SchemaError::Syntax("unterminated string")),
+            }
+        }
+    } else {
+        let end = rest.find([' ', '\t', '#']).unwrap_or(rest.len());
+        let value = match &rest[..end] {
+            "true" => SchemaValue::Bool(true),
+            "false" => SchemaValue::Bool(false),
+            value => SchemaValue::Int(
+                value
+                    .parse()
+                    .map_err(|_| SchemaError::InvalidValue(key.into()))?,
+            ),
+        };
+        (value, &rest[end..])
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(SchemaError::Trailing(rest.into()));
+    }
+    Ok(Some((key, value)))
+}
+
+/// Checks that a field is valid UTF-8, for `--validate-utf8`.
+#[inline]
+fn validate_utf8(field: &[u8], col: &'static str) -> Result<(), FieldError> {
+    match str::from_utf8(field) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(FieldError::Utf8 {
+            col,
+            value: field.into(),
+            valid_up_to: err.valid_up_to(),
+        }),
+    }
+}
+
+/// The key under which rows with an empty `Prod` are aggregated, so that they
+/// are not printed as a blank symbol.
+const EMPTY_PROD: &[u8] = b"<empty>";
+
+/// Returns the map key for a `Prod` value.
+#[inline]
+fn prod_key(prod: &[u8]) -> &[u8] {
+    if prod.is_empty() {
+        EMPTY_PROD
+    } else {
+        prod
+    }
+}
+
+/// Returns the map key for a row: its `--group-by` value, or with
+/// `--group-by <name>,bs`, that and its `B/S` value joined by a NUL. With
+/// `--normalize-keys`, the `--group-by` value is uppercased. Keys other than
+/// the value itself are built in `buf`.
+#[inline]
+fn group_key<'a>(
+    col: impl Fn(usize) -> &'a [u8],
+    idx: &ColIndices,
+    config: &Config,
+    buf: &'a mut Vec<u8>,
+) -> &'a [u8] {
+    let prod = col(idx.prod);
+    if !config.group_by_side && !config.normalize_keys {
+        return prod_key(prod);
+    }
+    buf.clear();
+    buf.extend_from_slice(prod_key(prod));
+    if config.normalize_keys && !prod.is_empty() {
+        buf.make_ascii_uppercase();
+    }
+    if !config.group_by_side {
+        return buf;
+    }
+    buf.push(b'\0');
+    buf.extend_from_slice(prod_key(col(idx.bs)));
+    buf
+}
+
+/// Splits a map key into its `--group-by` value and, with
+/// `--group-by <name>,bs`, its `B/S` value.
+#[inline]
+fn split_group_key<'a>(key: &'a [u8], config: &Config) -> (&'a [u8], Option<&'a [u8]>) {
+    if config.group_by_side {
+        if let Some(i) = memrchr(b'\0', key) {
+            return (&key[..i], Some(&key[i + 1..]));
+        }
+    }
+    (key, None)
+}
+
+/// Parses the quantity of a row, from `--qty-col` or by `--qty-reduce`, for
+/// checking it against `--max-qty` before it is aggregated.
+#[inline]
+fn row_qty<'a>(
+    col: impl Fn(usize) -> &'a [u8],
+    idx: &ColIndices,
+    config: &Config,
+) -> Result<u32, FieldError> {
+    let parse = |i, name| parse_qty(col(i), name, config.null_qty);
+    match idx.qty {
+        Some((i, name)) => parse(i, name),
+        None => Ok(config.qty_reduce.reduce(
+            parse(idx.ordqty, "OrdQty")?,
+            parse(idx.wrkqty, "WrkQty")?,
+            parse(idx.excqty, "ExcQty")?,
+        )),
+    }
+}
+
+/// Clamps the quantity of a row to `--max-qty` with `--max-qty-policy clamp`,
+/// which `matches_fields` has reported.
+#[inline]
+fn clamp_qty(qty: u32, config: &Config) -> u32 {
+    match config.max_qty {
+        Some(max) if config.max_qty_policy == MaxQtyPolicy::Clamp => qty.min(max),
+        _ => qty,
+    }
+}
+
+/// Parses a quantity field of the named column.
+#[inline]
+fn parse_u32(s: &[u8], col: &'static str) -> Result<u32, FieldError> {
+    // SAFETY: The grammar for u32::from_str_radix is all ASCII and it parses
+    // as bytes, rejecting any non-ASCII sequences, so it handles invalid UTF-8
+    // safely.
+    let s = unsafe { str::from_utf8_unchecked(s) };
+    s.parse().map_err(|_| FieldError::new(col, s.as_bytes()))
+}
+
+/// Whether the quantities of a row are ordered with `ExcQty <= WrkQty <=
+/// OrdQty`, as in well-formed data.
+#[inline]
+fn qty_ordered(ordqty: u32, wrkqty: u32, excqty: u32) -> bool {
+    excqty <= wrkqty && wrkqty <= ordqty
+}
+
+/// Parses a quantity field, which may be empty with `--null-qty-policy zero`.
+#[inline]
+fn parse_qty(s: &[u8], col: &'static str, null_qty: NullQty) -> Result<u32, FieldError> {
+    if s.is_empty() && null_qty == NullQty::Zero {
+        return Ok(0);
+    }
+    parse_u32(s, col)
+}
+
+/// Parses a `Price` field, rejecting values that are not finite, so that one
+/// malformed row cannot turn a VWAP into NaN or infinity.
+#[inline]
+fn parse_price(price: &[u8]) -> Result<f64, FieldError> {
+    str::from_utf8(price)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|price| price.is_finite())
+        .ok_or_else(|| FieldError::new("Price", price))
+}
+
+/// Parses a timestamp for the order rate of `--stats`, as a time of day
+/// formatted as `HH:MM:SS` or as seconds since the Unix epoch.
+#[inline]
+fn parse_timestamp(time: &[u8]) -> Result<u64, FieldError> {
+    if let Some(time) = parse_time(time) {
+        return Ok(time as u64);
+    }
+    if !time.is_empty() && time.iter().all(u8::is_ascii_digit) {
+        // SAFETY: The field is all ASCII digits.
+        if let Ok(secs) = unsafe { str::from_utf8_unchecked(time) }.parse() {
+            return Ok(secs);
+        }
+    }
+    Err(FieldError::new("Time", time))
+}
+
+/// Parses a time of day formatted as `HH:MM:SS` into seconds since midnight.
+#[inline]
+pub fn parse_time(time: &[u8]) -> Option<u32> {
+    let &[h1, h2, b':', m1, m2, b':', s1, s2] = time else {
+        return None;
+    };
+    let digits = [h1, h2, m1, m2, s1, s2];
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let [h, m, s] = [0, 2, 4].map(|i| ((digits[i] - b'0') * 10 + (digits[i + 1] - b'0')) as u32);
+    (h < 24 && m < 60 && s < 60).then_some(h * 3600 + m * 60 + s)
+}
+
+#[inline]
+fn memmap<P: AsRef<Path>>(path: P) -> Result<Mmap, Error> {
+    let path = path.as_ref();
+    if is_stdin(path) {
+        return Err(Error::Unsupported("cannot memory-map stdin"));
+    }
+    let mut file = File::open(path)?;
+    match Compression::detect(path, &mut file)? {
+        Some(Compression::Gzip) => Err(Error::Unsupported(
+            "cannot memory-map gzip-compressed input",
+        )),
+        Some(Compression::Zstd) => Err(Error::Unsupported(
+            "cannot memory-map zstd-compressed input",
+        )),
+        None => Ok(unsafe { Mmap::map(&file)? }),
+    }
+}
+
+/// Memory-maps the file like `memmap` and advises the kernel that it will be
+/// read sequentially and soon, so that it reads ahead aggressively.
+#[inline]
+fn memmap_sequential<P: AsRef<Path>>(path: P) -> Result<Mmap, Error> {
+    let mmap = memmap(path)?;
+    #[cfg(unix)]
+    {
+        mmap.advise(Advice::Sequential)?;
+        mmap.advise(Advice::WillNeed)?;
+    }
+    Ok(mmap)
+}
+
+#[inline]
+fn read_fulltext<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    match Input::open(&path)? {
+        Input::Plain(_) => Ok(fs::read(path)?),
+        mut decoder @ (Input::Gzip(_) | Input::Zstd(_)) => {
+            let mut text = Vec::new();
+            decoder.read_to_end(&mut text)?;
+            Ok(text)
+        }
+        Input::Stdin(_) => Err(Error::Unsupported(
+            "cannot read stdin as full text; use a streaming strategy",
+        )),
+    }
+}
+
+/// The buffer which `fulltext-reuse` reads into, which keeps its capacity
+/// across runs.
+static FULLTEXT_BUF: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Reads the whole input into a buffer, replacing its contents, but keeping
+/// its allocation when it is large enough.
+#[inline]
+fn read_fulltext_into<P: AsRef<Path>>(path: P, text: &mut Vec<u8>) -> Result<(), Error> {
+    text.clear();
+    match Input::open(&path)? {
+        Input::Stdin(_) => Err(Error::Unsupported(
+            "cannot read stdin as full text; use a streaming strategy",
+        )),
+        mut input => {
+            input.read_to_end(text)?;
+            Ok(())
+        }
+    }
+}
+
+/// A streaming input, which is transparently decompressed when it is gzip-
+/// or zstd-compressed. The path `-` denotes stdin.
+enum Input {
+    Plain(File),
+    Gzip(GzDecoder<File>),
+    Zstd(zstd::Decoder<'static, BufReader<File>>),
+    Stdin(Stdin),
+}
+
+impl Input {
+    fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if is_stdin(path) {
+            return Ok(Input::Stdin(io::stdin()));
+        }
+        let mut file = File::open(path)?;
+        match Compression::detect(path, &mut file)? {
+            Some(Compression::Gzip) => Ok(Input::Gzip(GzDecoder::new(file))),
+            Some(Compression::Zstd) => Ok(Input::Zstd(zstd::Decoder::new(file)?)),
+            None => Ok(Input::Plain(file)),
+        }
+    }
+}
+
+impl Read for Input {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Plain(file) => file.read(buf),
+            Input::Gzip(decoder) => decoder.read(buf),
+            Input::Zstd(decoder) => decoder.read(buf),
+            Input::Stdin(stdin) => stdin.read(buf),
+        }
+    }
+}
+
+#[inline]
+pub fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// A compression format of an input.
+#[derive(Clone, Copy)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects compressed files by a `.gz` or `.zst` extension or by the magic
+    /// bytes of the format. The file is rewound to the start afterwards.
+    fn detect(path: &Path, file: &mut File) -> io::Result<Option<Self>> {
+        match path.extension() {
+            Some(ext) if ext == "gz" => return Ok(Some(Compression::Gzip)),
+            Some(ext) if ext == "zst" => return Ok(Some(Compression::Zstd)),
+            _ => {}
+        }
+        let mut magic = Vec::with_capacity(4);
+        Read::by_ref(file).take(4).read_to_end(&mut magic)?;
+        file.rewind()?;
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Ok(Some(Compression::Gzip))
+        } else if magic == [0x28, 0xb5, 0x2f, 0xfd] {
+            Ok(Some(Compression::Zstd))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Aggregates rows with keys borrowed from the text. Normalized keys are not
+/// in the text, so `--normalize-keys` falls back to `calc_key_clone`, which
+/// allocates a key for every product.
+#[inline]
+fn calc_key_ref<T: AsRef<[u8]>, S: Sink>(
+    text: T,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    if config.normalize_keys {
+        return calc_key_clone(text, config, sink);
+    }
+    let text = skip_rows(text.as_ref(), config);
+    if text.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    let mut lines = split_lines(text).peekable();
+    let header = if config.no_header {
+        *lines.peek().unwrap()
+    } else {
+        lines.next().unwrap()
+    };
+    let first_row = config.first_row_line();
+    let (idx, header_len) = ColIndices::from_header(header, config)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+
+    let mut products = HashMap::<&[u8], ProductData>::with_capacity(config.products_hint);
+    let mut cols = Vec::with_capacity(useful_cols);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, Some(text.len() as u64));
+    for (i, line) in lines.enumerate() {
+        if counts.rows() == config.limit {
+            break;
+        }
+        progress.tick(line.len() + 1);
+        if line.is_empty() {
+            counts.blank += 1;
+            continue;
+        }
+        if config.is_ignored(line, header) {
+            counts.ignored += 1;
+            continue;
+        }
+        cols.clear();
+        split_fields(&mut cols, line, useful_cols, config);
+        if !config
+            .check_width(line, cols.len(), useful_cols, header_len)
+            .map_err(|err| err.at_line(i + first_row))?
+        {
+            counts.malformed += 1;
+        } else if config
+            .matches_row(&cols, &idx, || i + first_row)
+            .map_err(|err| err.at_line(i + first_row))?
+        {
+            counts.processed += 1;
+            let prod = products.entry(prod_key(cols[idx.prod])).or_default();
+            if !prod
+                .process_row(&cols, &idx, i + first_row, config)
+                .map_err(|err| err.at_line(i + first_row))?
+            {
+                counts.add_violation(i + first_row);
+            }
+        } else {
+            counts.skipped += 1;
+        }
+    }
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (*k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Aggregates newline-aligned chunks of the text on the rayon pool into maps
+/// with keys borrowed from the text, then merges them.
+#[inline]
+fn calc_key_ref_parallel<T: AsRef<[u8]>, S: Sink>(
+    text: T,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let text = text.as_ref();
+    if text.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    let (header, body_start) = split_header(text, config);
+    let (idx, header_len) = ColIndices::from_header(header, config)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+    let body = take_lines(&text[body_start..], header, config);
+    let first_row = config.first_row_line();
+
+    let (products, counts) = split_line_chunks(body, rayon::current_num_threads())
+        .into_par_iter()
+        .map(|chunk| {
+            let mut products = HashMap::<&[u8], ProductData>::with_capacity(config.products_hint);
+            let mut counts = RowCounts::default();
+            let mut cols = Vec::with_capacity(useful_cols);
+            let chunk_line = config
+                .trace
+                .then(|| memchr_iter(b'\n', &body[..chunk.start]).count() + first_row);
+            for (i, line) in split_lines(&body[chunk.clone()]).enumerate() {
+                if line.is_empty() {
+                    counts.blank += 1;
+                    continue;
+                }
+                if config.is_ignored(line, header) {
+                    counts.ignored += 1;
+                    continue;
+                }
+                cols.clear();
+                split_fields(&mut cols, line, useful_cols, config);
+                // Lines are only counted on error or when reported, so that
+                // chunks remain independent, except with `--trace`, which
+                // needs the line of every row.
+                let line_num = || {
+                    chunk_line.unwrap_or_else(|| {
+                        memchr_iter(b'\n', &body[..chunk.start]).count() + first_row
+                    }) + i
+                };
+                let trace_line = || if config.trace { line_num() } else { 0 };
+                let at_line = |err: FieldError| err.at_line(line_num());
+                if !config
+                    .check_width(line, cols.len(), useful_cols, header_len)
+                    .map_err(at_line)?
+                {
+                    counts.malformed += 1;
+                } else if config.matches_row(&cols, &idx, line_num).map_err(at_line)? {
+                    counts.processed += 1;
+                    let prod = products.entry(prod_key(cols[idx.prod])).or_default();
+                    if !prod
+                        .process_row(&cols, &idx, trace_line(), config)
+                        .map_err(at_line)?
+                    {
+                        counts.add_violation(line_num());
+                    }
+                } else {
+                    counts.skipped += 1;
+                }
+            }
+            Ok::<_, Error>((products, counts))
+        })
+        .try_reduce(
+            || (HashMap::new(), RowCounts::default()),
+            |(mut products, mut counts), (chunk_products, chunk_counts)| {
+                for (prod, data) in chunk_products {
+                    products.entry(prod).or_default().merge(&data);
+                }
+                counts.merge(&chunk_counts);
+                Ok((products, counts))
+            },
+        )?;
+    sink.finish(products.iter().map(|(k, v)| (*k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Truncates text after its first `--limit` rows, excluding blank and
+/// ignored lines.
+fn take_lines<'a>(text: &'a [u8], header: &[u8], config: &Config) -> &'a [u8] {
+    let mut rows = 0;
+    let mut start = 0;
+    while rows < config.limit && start < text.len() {
+        let end = memchr(b'\n', &text[start..]).map_or(text.len(), |i| start + i + 1);
+        let line = trim_eol(&text[start..end]);
+        if !line.is_empty() && !config.is_ignored(line, header) {
+            rows += 1;
+        }
+        start = end;
+    }
+    &text[..start]
+}
+
+/// Splits text into at most `n` chunks of roughly equal size, with each
+/// boundary moved forward to just after the next newline so that no line
+/// straddles two chunks.
+fn split_line_chunks(text: &[u8], n: usize) -> Vec<Range<usize>> {
+    let mut chunks = Vec::with_capacity(n);
+    let mut start = 0;
+    for i in 1..=n {
+        if start >= text.len() {
+            break;
+        }
+        let target = (text.len() * i / n).max(start);
+        let end = match memchr(b'\n', &text[target..]) {
+            Some(j) => target + j + 1,
+            None => text.len(),
+        };
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks
+}
+
+#[inline]
+fn calc_key_clone<T: AsRef<[u8]>, S: Sink>(
+    text: T,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let text = skip_rows(text.as_ref(), config);
+    if text.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    let mut lines = split_lines(text).peekable();
+    let header = if config.no_header {
+        *lines.peek().unwrap()
+    } else {
+        lines.next().unwrap()
+    };
+    let first_row = config.first_row_line();
+    let (idx, header_len) = ColIndices::from_header(header, config)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut cols = Vec::with_capacity(useful_cols);
+    let mut key = Vec::new();
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, Some(text.len() as u64));
+    for (i, line) in lines.enumerate() {
+        if counts.rows() == config.limit {
+            break;
+        }
+        progress.tick(line.len() + 1);
+        if line.is_empty() {
+            counts.blank += 1;
+            continue;
+        }
+        if config.is_ignored(line, header) {
+            counts.ignored += 1;
+            continue;
+        }
+        cols.clear();
+        split_fields(&mut cols, line, useful_cols, config);
+        if !config
+            .check_width(line, cols.len(), useful_cols, header_len)
+            .map_err(|err| err.at_line(i + first_row))?
+        {
+            counts.malformed += 1;
+        } else if config
+            .matches_row(&cols, &idx, || i + first_row)
+            .map_err(|err| err.at_line(i + first_row))?
+        {
+            counts.processed += 1;
+            let prod = products
+                .entry_ref(group_key(|i| cols[i], &idx, config, &mut key))
+                .or_default();
+            if !prod
+                .process_row(&cols, &idx, i + first_row, config)
+                .map_err(|err| err.at_line(i + first_row))?
+            {
+                counts.add_violation(i + first_row);
+            }
+        } else {
+            counts.skipped += 1;
+        }
+    }
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Reads a file which is being appended to, like `tail -f`, for `--follow`,
+/// with the line reader of `read`, the only strategy which supports it. At the
+/// end of the file, the products are re-printed if rows have been added since
+/// they last were, and it is polled again after the interval, until `--limit`
+/// rows are read. A partial line at the end is held until it is completed.
+fn calc_follow<S: Sink>(
+    path: &Path,
+    interval: Duration,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    if is_stdin(path) {
+        return Err(Error::Unsupported("--follow cannot follow stdin"));
+    }
+    let mut file = File::open(path)?;
+    match Compression::detect(path, &mut file)? {
+        Some(Compression::Gzip) => {
+            return Err(Error::Unsupported(
+                "--follow cannot follow gzip-compressed input",
+            ));
+        }
+        Some(Compression::Zstd) => {
+            return Err(Error::Unsupported(
+                "--follow cannot follow zstd-compressed input",
+            ));
+        }
+        None => {}
+    }
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    for _ in 0..config.skip_rows {
+        read_line_following(&mut reader, &mut line, interval)?;
+    }
+    read_line_following(&mut reader, &mut line, interval)?;
+    let header = trim_eol(&line).to_vec();
+    let (idx, header_len) = ColIndices::from_header(&header, config)?;
+    phases::header_parsed();
+    let mut rows = RowFilter::new(header, idx, header_len);
+
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    // The rows counted before the last `--follow-reset`.
+    let mut reset_rows = 0;
+    let mut changed = false;
+    let mut key = Vec::new();
+    let mut line_num = config.first_row_line() - 1;
+    // With `--no-header`, the header line is also the first row.
+    let mut reread = config.no_header;
+    // The limit is checked before waiting for another row.
+    while reset_rows + counts.rows() != config.limit {
+        if !mem::take(&mut reread) {
+            line.clear();
+            while !line.ends_with(b"\n") {
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    if mem::take(&mut changed) {
+                        sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+                        if config.follow_reset {
+                            products.clear();
+                            reset_rows += counts.rows();
+                            counts = RowCounts::default();
+                        }
+                    }
+                    thread::sleep(interval);
+                }
+            }
+        }
+        line_num += 1;
+        let line = trim_eol(&line);
+        progress.tick(line.len() + 1);
+        changed = true;
+        rows.filter_line(line, line_num, &mut counts, config, |cols, idx, counts| {
+            if !config.count_only {
+                let prod = products
+                    .entry_ref(group_key(|i| cols[i], idx, config, &mut key))
+                    .or_default();
+                if !prod
+                    .process_row(cols, idx, line_num, config)
+                    .map_err(|err| err.at_line(line_num))?
+                {
+                    counts.add_violation(line_num);
+                }
+            }
+            Ok(())
+        })?;
+    }
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Reads the next whole line into `line` for `--follow`, waiting at the end
+/// of the file for the rest of it to be appended.
+fn read_line_following(
+    reader: &mut impl BufRead,
+    line: &mut Vec<u8>,
+    interval: Duration,
+) -> io::Result<()> {
+    line.clear();
+    while !line.ends_with(b"\n") {
+        if reader.read_until(b'\n', line)? == 0 {
+            thread::sleep(interval);
+        }
+    }
+    Ok(())
+}
+
+#[inline]
+fn calc_read<R: Read, S: Sink>(
+    inputs: &[PathBuf],
+    open: impl Fn(&Path) -> Result<R, Error>,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let mut reader = BufReader::new(open(path)?);
+        skip_rows_buffered(&mut reader, config)?;
+
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(HeaderError::Empty.into());
+        }
+        let header = trim_eol(&line).to_vec();
+        let (idx, header_len) = ColIndices::from_header(&header, config)?;
+        let idx = headers.check(idx)?;
+        phases::header_parsed();
+        let useful_cols = idx.useful_cols();
+
+        let mut cols_empty: Vec<&'static [u8]> = Vec::with_capacity(useful_cols);
+        let mut key = Vec::new();
+        let mut line_num = config.first_row_line() - 1;
+        // With `--no-header`, the header line is also the first row.
+        let mut reread = config.no_header;
+        loop {
+            if !mem::take(&mut reread) {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+            }
+            line_num += 1;
+            let line = trim_eol(&line);
+            if counts.rows() == config.limit {
+                break;
+            }
+            progress.tick(line.len() + 1);
+            if line.is_empty() {
+                counts.blank += 1;
+                continue;
+            }
+            if config.is_ignored(line, &header) {
+                counts.ignored += 1;
+                continue;
+            }
+            let mut cols = cols_empty;
+            split_fields(&mut cols, line, useful_cols, config);
+            if !config
+                .check_width(line, cols.len(), useful_cols, header_len)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.malformed += 1;
+            } else if config
+                .matches_row(&cols, &idx, || line_num)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.processed += 1;
+                if !config.count_only {
+                    let prod = products
+                        .entry_ref(group_key(|i| cols[i], &idx, config, &mut key))
+                        .or_default();
+                    if !prod
+                        .process_row(&cols, &idx, line_num, config)
+                        .map_err(|err| err.at_line(line_num))?
+                    {
+                        counts.add_violation(line_num);
+                    }
+                }
+            } else {
+                counts.skipped += 1;
+            }
+            cols_empty = cols.into_iter().take(0).map(|_| &[][..]).collect();
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+#[inline]
+fn calc_read_memchr<R: Read, S: Sink>(
+    inputs: &[PathBuf],
+    open: impl Fn(&Path) -> Result<R, Error>,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let mut reader = BufReader::new(open(path)?);
+        skip_rows_buffered(&mut reader, config)?;
+
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(HeaderError::Empty.into());
+        }
+        let header = trim_eol(&line).to_vec();
+        let (idx, header_len) = ColIndices::from_header(&header, config)?;
+        let idx = headers.check(idx)?;
+        phases::header_parsed();
+        let useful_cols = idx.useful_cols();
+
+        let mut cols: Vec<usize> = Vec::with_capacity(useful_cols);
+        let mut key = Vec::new();
+        let mut line_num = config.first_row_line() - 1;
+        // With `--no-header`, the header line is also the first row.
+        let mut reread = config.no_header;
+        loop {
+            if !mem::take(&mut reread) {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+            }
+            line_num += 1;
+            let line = trim_eol(&line);
+            if counts.rows() == config.limit {
+                break;
+            }
+            progress.tick(line.len() + 1);
+            if line.is_empty() {
+                counts.blank += 1;
+                continue;
+            }
+            if config.is_ignored(line, &header) {
+                counts.ignored += 1;
+                continue;
+            }
+            cols.clear();
+            cols.push(usize::MAX);
+            if config.rfc4180 {
+                cols.extend(QuotedDelimiters::new(line, config.delimiter).take(useful_cols));
+            } else {
+                cols.extend(memchr_iter(config.delimiter, line).take(useful_cols));
+            }
+            cols.push(line.len());
+            let col = |col| {
+                let field = get_col(line, &cols, col);
+                if config.rfc4180 {
+                    unquote(field)
+                } else {
+                    field
+                }
+            };
+            if !config
+                .check_width(line, cols.len() - 1, useful_cols, header_len)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.malformed += 1;
+            } else if config
+                .matches_fields(col, &idx, || line_num)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.processed += 1;
+                if !config.count_only {
+                    let prod = products
+                        .entry_ref(group_key(col, &idx, config, &mut key))
+                        .or_default();
+                    if !prod
+                        .process_fields(col, &idx, line_num, config)
+                        .map_err(|err| err.at_line(line_num))?
+                    {
+                        counts.add_violation(line_num);
+                    }
+                }
+            } else {
+                counts.skipped += 1;
+            }
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Splits lines with `BufRead::split` and records the offsets of the
+/// delimiters in each, instead of borrowed fields, so that the offsets `Vec`
+/// can be reused across lines without borrowing from them.
+#[inline]
+fn calc_read_offsets<R: Read, S: Sink>(
+    inputs: &[PathBuf],
+    open: impl Fn(&Path) -> Result<R, Error>,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let mut lines = BufReader::new(open(path)?).split(b'\n');
+        for _ in 0..config.skip_rows {
+            if lines.next().transpose()?.is_none() {
+                break;
+            }
+        }
+        let first = lines.next().transpose()?.ok_or(HeaderError::Empty)?;
+        let header = trim_eol(&first);
+        let (idx, header_len) = ColIndices::from_header(header, config)?;
+        let idx = headers.check(idx)?;
+        phases::header_parsed();
+        let useful_cols = idx.useful_cols();
+        let first_row = config.first_row_line();
+        // With `--no-header`, the header line is also the first row.
+        let rows = config.no_header.then(|| Ok(first.clone()));
+
+        let mut offsets: Vec<usize> = Vec::with_capacity(useful_cols + 1);
+        let mut key = Vec::new();
+        for (i, line) in rows.into_iter().chain(lines).enumerate() {
+            let line = line?;
+            let line = trim_eol(&line);
+            if counts.rows() == config.limit {
+                break;
+            }
+            progress.tick(line.len() + 1);
+            if line.is_empty() {
+                counts.blank += 1;
+                continue;
+            }
+            if config.is_ignored(line, header) {
+                counts.ignored += 1;
+                continue;
+            }
+            offsets.clear();
+            offsets.push(usize::MAX);
+            if config.rfc4180 {
+                offsets.extend(QuotedDelimiters::new(line, config.delimiter).take(useful_cols));
+            } else {
+                offsets.extend(memchr_iter(config.delimiter, line).take(useful_cols));
+            }
+            offsets.push(line.len());
+            let col = |i| {
+                let field = get_col(line, &offsets, i);
+                if config.rfc4180 {
+                    unquote(field)
+                } else {
+                    field
+                }
+            };
+            if !config
+                .check_width(line, offsets.len() - 1, useful_cols, header_len)
+                .map_err(|err| err.at_line(i + first_row))?
+            {
+                counts.malformed += 1;
+            } else if config
+                .matches_fields(col, &idx, || i + first_row)
+                .map_err(|err| err.at_line(i + first_row))?
+            {
+                counts.processed += 1;
+                if !config.count_only {
+                    let prod = products
+                        .entry_ref(group_key(col, &idx, config, &mut key))
+                        .or_default();
+                    if !prod
+                        .process_fields(col, &idx, i + first_row, config)
+                        .map_err(|err| err.at_line(i + first_row))?
+                    {
+                        counts.add_violation(i + first_row);
+                    }
+                }
+            } else {
+                counts.skipped += 1;
+            }
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// The names of the columns which are located by fixed names in the header.
+const COLUMN_NAMES: [&str; 7] = [
+    "Source", "B/S", "OrdQty", "WrkQty", "ExcQty", "Time", "Price",
+];
+
+/// Strips a UTF-8 byte order mark from the start of a header, as in some
+/// Windows exports, and rejects UTF-16, which would otherwise be misparsed.
+#[inline]
+fn strip_bom(header: &[u8]) -> Result<&[u8], HeaderError> {
+    if header.starts_with(b"\xFF\xFE") || header.starts_with(b"\xFE\xFF") {
+        return Err(HeaderError::Utf16);
+    }
+    Ok(header.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(header))
+}
+
+/// Splits text into lines without their terminators. A final newline ends
+/// the last line, rather than starting an empty one.
+#[inline]
+fn split_lines(text: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let text = text.strip_suffix(b"\n").unwrap_or(text);
+    text.split(|&b| b == b'\n').map(trim_eol)
+}
+
+/// Finds the header line of a text and the offset of its first row. With
+/// `--no-header`, the first line is located as the header, but is also the
+/// first row.
+#[inline]
+fn split_header<'a>(text: &'a [u8], config: &Config) -> (&'a [u8], usize) {
+    let start = skip_lines_offset(text, config.skip_rows).unwrap_or(text.len());
+    let header_end = start + memchr(b'\n', &text[start..]).unwrap_or(text.len() - start);
+    let header = trim_eol(&text[start..header_end]);
+    let body_start = if config.no_header {
+        start
+    } else {
+        (header_end + 1).min(text.len())
+    };
+    (header, body_start)
+}
+
+/// Finds where the line after the first `n` lines of a text starts, or when
+/// the text ends before it, returns the number of lines it holds instead.
+fn skip_lines_offset(text: &[u8], n: usize) -> Result<usize, usize> {
+    let mut count = 0;
+    let mut start = 0;
+    for end in memchr_iter(b'\n', text) {
+        if count == n {
+            break;
+        }
+        count += 1;
+        start = end + 1;
+    }
+    if count == n && start < text.len() {
+        Ok(start)
+    } else {
+        Err(count)
+    }
+}
+
+/// Discards the `--skip-rows` lines before the header of a text.
+#[inline]
+fn skip_rows<'a>(text: &'a [u8], config: &Config) -> &'a [u8] {
+    let start = skip_lines_offset(text, config.skip_rows).unwrap_or(text.len());
+    &text[start..]
+}
+
+/// Discards the `--skip-rows` lines before the header of a buffered reader.
+fn skip_rows_buffered(reader: &mut impl BufRead, config: &Config) -> io::Result<()> {
+    for _ in 0..config.skip_rows {
+        if reader.skip_until(b'\n')? == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Strips a trailing `\n` or `\r\n` line terminator.
+#[inline]
+fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Scans the whole text for delimiters and newlines in a single pass with
+/// `memchr2`, instead of first splitting lines and then fields.
+#[inline]
+fn calc_simd<T: AsRef<[u8]>, S: Sink>(text: T, config: &Config, sink: &mut S) -> Result<(), Error> {
+    if config.rfc4180 {
+        return Err(Error::Unsupported(
+            "the simd strategy does not support --rfc4180",
+        ));
+    }
+    let text = text.as_ref();
+    if text.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    let (header, body_start) = split_header(text, config);
+    let (idx, header_len) = ColIndices::from_header(header, config)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+
+    let mut products = HashMap::<&[u8], ProductData>::with_capacity(config.products_hint);
+    // The offsets of the newline preceding the current line, of the
+    // delimiters seen so far in it, and, once complete, of its end. The first
+    // row of a headerless input is preceded by a newline at offset -1, which
+    // wraps.
+    let mut bounds: Vec<usize> = Vec::with_capacity(useful_cols + 1);
+    let mut cols = Vec::with_capacity(useful_cols);
+    let mut line_num = config.first_row_line() - 1;
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, Some(text.len() as u64));
+    bounds.push(body_start.wrapping_sub(1));
+    // A final newline ends the last line, rather than starting an empty one.
+    let unterminated = body_start < text.len() && !text.ends_with(b"\n");
+    let boundaries = memchr2_iter(config.delimiter, b'\n', &text[body_start..])
+        .map(|i| body_start + i)
+        .chain(unterminated.then_some(text.len()));
+    for i in boundaries {
+        if i < text.len() && text[i] != b'\n' {
+            if bounds.len() <= useful_cols {
+                bounds.push(i);
+            }
+            continue;
+        }
+        line_num += 1;
+        let start = bounds[0].wrapping_add(1);
+        let end = if i > start && text[i - 1] == b'\r' {
+            i - 1
+        } else {
+            i
+        };
+        if counts.rows() == config.limit {
+            break;
+        }
+        progress.tick(i.wrapping_sub(bounds[0]));
+        if end <= start {
+            counts.blank += 1;
+        } else if config.is_ignored(&text[start..end], header) {
+            counts.ignored += 1;
+        } else {
+            bounds.push(end);
+            cols.clear();
+            cols.extend(bounds.windows(2).map(|w| &text[w[0].wrapping_add(1)..w[1]]));
+            if !config
+                .check_width(&text[start..end], cols.len(), useful_cols, header_len)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.malformed += 1;
+            } else if config
+                .matches_row(&cols, &idx, || line_num)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.processed += 1;
+                let prod = products.entry(prod_key(cols[idx.prod])).or_default();
+                if !prod
+                    .process_row(&cols, &idx, line_num, config)
+                    .map_err(|err| err.at_line(line_num))?
+                {
+                    counts.add_violation(line_num);
+                }
+            } else {
+                counts.skipped += 1;
+            }
+        }
+        bounds.clear();
+        bounds.push(i);
+    }
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (*k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Splits lines and fields with the `bstr` methods, `lines` and `split_str`,
+/// instead of `memchr`, and copies keys like `calc_key_clone`.
+#[inline]
+fn calc_bstr<T: AsRef<[u8]>, S: Sink>(text: T, config: &Config, sink: &mut S) -> Result<(), Error> {
+    if config.rfc4180 {
+        return Err(Error::Unsupported(
+            "the bstr strategy does not support --rfc4180",
+        ));
+    }
+    let text = skip_rows(text.as_ref(), config);
+    if text.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    let mut lines = bstr::ByteSlice::lines(text).peekable();
+    let header = if config.no_header {
+        *lines.peek().unwrap()
+    } else {
+        lines.next().unwrap()
+    };
+    let first_row = config.first_row_line();
+    let (idx, header_len) = ColIndices::from_header(header, config)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+
+    let delimiter = [config.delimiter];
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut cols = Vec::with_capacity(useful_cols);
+    let mut key = Vec::new();
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, Some(text.len() as u64));
+    for (i, line) in lines.enumerate() {
+        if counts.rows() == config.limit {
+            break;
+        }
+        progress.tick(line.len() + 1);
+        if line.is_empty() {
+            counts.blank += 1;
+            continue;
+        }
+        if config.is_ignored(line, header) {
+            counts.ignored += 1;
+            continue;
+        }
+        cols.clear();
+        cols.extend(bstr::ByteSlice::split_str(line, &delimiter).take(useful_cols));
+        if !config
+            .check_width(line, cols.len(), useful_cols, header_len)
+            .map_err(|err| err.at_line(i + first_row))?
+        {
+            counts.malformed += 1;
+        } else if config
+            .matches_row(&cols, &idx, || i + first_row)
+            .map_err(|err| err.at_line(i + first_row))?
+        {
+            counts.processed += 1;
+            let prod = products
+                .entry_ref(group_key(|i| cols[i], &idx, config, &mut key))
+                .or_default();
+            if !prod
+                .process_row(&cols, &idx, i + first_row, config)
+                .map_err(|err| err.at_line(i + first_row))?
+            {
+                counts.add_violation(i + first_row);
+            }
+        } else {
+            counts.skipped += 1;
+        }
+    }
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Finds lines and fields in the whole text with `memchr_iter` and records
+/// the offsets of the delimiters in each line, like `calc_read_memchr`, but
+/// borrows lines from the text instead of copying them into a buffer.
+#[inline]
+fn calc_memmap_memchr<T: AsRef<[u8]>, S: Sink>(
+    text: T,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let text = text.as_ref();
+    if text.is_empty() {
+        return Err(HeaderError::Empty.into());
+    }
+    let (header, body_start) = split_header(text, config);
+    let (idx, header_len) = ColIndices::from_header(header, config)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+    let body = &text[body_start..];
+    let first_row = config.first_row_line();
+
+    let mut products = HashMap::<&[u8], ProductData>::with_capacity(config.products_hint);
+    let mut cols: Vec<usize> = Vec::with_capacity(useful_cols + 2);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, Some(text.len() as u64));
+    // A final newline ends the last line, rather than starting an empty one.
+    let unterminated = !body.is_empty() && !body.ends_with(b"\n");
+    let line_ends = memchr_iter(b'\n', body).chain(unterminated.then_some(body.len()));
+    let mut start = 0;
+    for (i, end) in line_ends.enumerate() {
+        let line_num = i + first_row;
+        let line = trim_eol(&body[start..end]);
+        start = end + 1;
+        if counts.rows() == config.limit {
+            break;
+        }
+        progress.tick(line.len() + 1);
+        if line.is_empty() {
+            counts.blank += 1;
+            continue;
+        }
+        if config.is_ignored(line, header) {
+            counts.ignored += 1;
+            continue;
+        }
+        cols.clear();
+        cols.push(usize::MAX);
+        if config.rfc4180 {
+            cols.extend(QuotedDelimiters::new(line, config.delimiter).take(useful_cols));
+        } else {
+            cols.extend(memchr_iter(config.delimiter, line).take(useful_cols));
+        }
+        cols.push(line.len());
+        let col = |col| {
+            let field = get_col(line, &cols, col);
+            if config.rfc4180 {
+                unquote(field)
+            } else {
+                field
+            }
+        };
+        if !config
+            .check_width(line, cols.len() - 1, useful_cols, header_len)
+            .map_err(|err| err.at_line(line_num))?
+        {
+            counts.malformed += 1;
+        } else if config
+            .matches_fields(col, &idx, || line_num)
+            .map_err(|err| err.at_line(line_num))?
+        {
+            counts.processed += 1;
+            let prod = products.entry(prod_key(col(idx.prod))).or_default();
+            if !prod
+                .process_fields(col, &idx, line_num, config)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.add_violation(line_num);
+            }
+        } else {
+            counts.skipped += 1;
+        }
+    }
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (*k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Gets a field from the offsets of the delimiters around it, where the
+/// first field is preceded by a delimiter at `usize::MAX`, which wraps to
+/// start it at 0.
+#[inline]
+fn get_col<'a>(line: &'a [u8], cols: &[usize], col: usize) -> &'a [u8] {
+    &line[cols[col].wrapping_add(1)..cols[col + 1]]
+}
+
+/// Splits up to the first `n` fields of a line into `cols`.
+#[inline]
+fn split_fields<'a>(cols: &mut Vec<&'a [u8]>, line: &'a [u8], n: usize, config: &Config) {
+    if config.rfc4180 {
+        cols.extend(split_quoted(line, config.delimiter).take(n));
+    } else {
+        cols.extend(line.split(|&b| b == config.delimiter).take(n));
+    }
+}
+
+/// Splits a line into fields, where a field may be enclosed in double quotes
+/// to contain the delimiter, and `""` within a quoted field is an escaped
+/// quote, as in RFC 4180. The enclosing quotes are stripped, but escaped quotes
+/// are left doubled, so that fields can still borrow from the line.
+#[inline]
+fn split_quoted(line: &[u8], delimiter: u8) -> impl Iterator<Item = &[u8]> {
+    let mut start = 0;
+    QuotedDelimiters::new(line, delimiter)
+        .map(Some)
+        .chain(iter::once(None))
+        .map(move |end| {
+            let end = end.unwrap_or(line.len());
+            let field = &line[start..end];
+            start = end + 1;
+            unquote(field)
+        })
+}
+
+/// Strips the enclosing double quotes from a field, if it has them.
+#[inline]
+fn unquote(field: &[u8]) -> &[u8] {
+    match field {
+        [b'"', inner @ .., b'"'] => inner,
+        _ => field,
+    }
+}
+
+/// An iterator over the positions of delimiters in a line, which are not
+/// within a double-quoted field.
+struct QuotedDelimiters<'a> {
+    line: &'a [u8],
+    pos: usize,
+    delimiter: u8,
+}
+
+impl<'a> QuotedDelimiters<'a> {
+    #[inline]
+    fn new(line: &'a [u8], delimiter: u8) -> Self {
+        QuotedDelimiters {
+            line,
+            pos: 0,
+            delimiter,
+        }
+    }
+}
+
+impl Iterator for QuotedDelimiters<'_> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        // An escaped quote toggles the state twice, so needs no special case.
+        let mut quoted = false;
+        while let Some(&b) = self.line.get(self.pos) {
+            self.pos += 1;
+            if b == b'"' {
+                quoted = !quoted;
+            } else if b == self.delimiter && !quoted {
+                return Some(self.pos - 1);
+            }
+        }
+        None
+    }
+}
+
+struct LineReader<R> {
+    reader: R,
+    buf: Box<[u8]>,
+    len: usize,
+    cur: usize,
+    line: Vec<u8>,
+    /// A line to return again from the next `advance`.
+    pending: Option<LineSpan>,
+}
+
+const BUF_CAP: usize = 32 * 1024;
+
+impl<R: Read> LineReader<R> {
+    fn new(reader: R, capacity: usize) -> Self {
+        LineReader {
+            reader,
+            buf: vec![0; capacity].into_boxed_slice(),
+            len: 0,
+            cur: 0,
+            line: Vec::with_capacity(1024),
+            pending: None,
+        }
+    }
+
+    /// Reads the header line, which, with `--no-header`, is the first row, so
+    /// is then returned again by the next `advance`.
+    fn read_header(&mut self, config: &Config) -> Result<Vec<u8>, Error> {
+        for _ in 0..config.skip_rows {
+            if self.advance()?.is_none() {
+                break;
+            }
+        }
+        let span = self.advance()?.ok_or(HeaderError::Empty)?;
+        let header = self.get(&span).to_vec();
+        if config.no_header {
+            self.pending = Some(span);
+        }
+        Ok(header)
+    }
+
+    #[inline]
+    fn next_line(&mut self) -> io::Result<Option<&[u8]>> {
+        Ok(self.advance()?.map(|span| self.get(&span)))
+    }
+
+    /// Reads the next line, but returns where it is held instead of borrowing
+    /// it, so that callers can decide to skip it and read another.
+    #[inline]
+    fn advance(&mut self) -> io::Result<Option<LineSpan>> {
+        if let Some(span) = self.pending.take() {
+            return Ok(Some(span));
+        }
+        self.line.clear();
+        loop {
+            match memchr(b'\n', &self.buf[self.cur..self.len]) {
+                Some(i) => {
+                    let start = self.cur;
+                    self.cur += i + 1;
+                    if self.line.is_empty() {
+                        return Ok(Some(LineSpan::Buf(start..start + i)));
+                    } else {
+                        self.line.extend_from_slice(&self.buf[start..start + i]);
+                        return Ok(Some(LineSpan::Carried));
+                    }
+                }
+                None => {
+                    self.line.extend_from_slice(&self.buf[self.cur..self.len]);
+                    self.cur = self.len;
+                }
+            }
+            if self.cur >= self.len {
+                self.len = read_retrying(&mut self.reader, &mut self.buf[..])?;
+                self.cur = 0;
+                if self.len == 0 {
+                    if self.line.is_empty() {
+                        return Ok(None);
+                    } else {
+                        return Ok(Some(LineSpan::Carried));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the line last read by `advance`, without its line ending.
+    #[inline]
+    fn get(&self, span: &LineSpan) -> &[u8] {
+        trim_eol(match span {
+            LineSpan::Buf(range) => &self.buf[range.clone()],
+            LineSpan::Carried => &self.line,
+        })
+    }
+}
+
+/// Reads once into `buf`, retrying reads interrupted by a signal before
+/// reading anything. Reads may be short, as from a pipe, so callers read
+/// until one returns 0.
+#[inline]
+fn read_retrying<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            res => return res,
+        }
+    }
+}
+
+/// Where a `LineReader` holds the line it last read.
+enum LineSpan {
+    /// The line is within the read buffer.
+    Buf(Range<usize>),
+    /// The line straddled reads, so was copied out of the buffer.
+    Carried,
+}
+
+/// Reads the rows of an input one at a time, with their fields located by
+/// the columns of the header, for aggregating rows in other ways than by
+/// product.
+pub struct RowReader<R> {
+    reader: LineReader<R>,
+    config: Config,
+    header: Vec<u8>,
+    idx: ColIndices,
+    header_len: usize,
+    useful_cols: usize,
+    offsets: Vec<usize>,
+    line_num: usize,
+}
+
+impl<R: Read> RowReader<R> {
+    /// Reads the header and locates its columns.
+    pub fn new(reader: R, config: &Config) -> Result<Self, Error> {
+        let mut reader = LineReader::new(reader, config.buf_size);
+        let header = reader.read_header(config)?;
+        let (idx, header_len) = ColIndices::from_header(&header, config)?;
+        let useful_cols = idx.useful_cols();
+        Ok(RowReader {
+            reader,
+            config: config.clone(),
+            header,
+            idx,
+            header_len,
+            useful_cols,
+            offsets: Vec::with_capacity(useful_cols + 2),
+            line_num: config.first_row_line() - 1,
+        })
+    }
+
+    /// The columns located in the header.
+    pub fn columns(&self) -> &ColIndices {
+        &self.idx
+    }
+
+    /// Reads the next row, skipping blank and ignored lines and rows too short
+    /// to locate every column, unless `--strict` is given, when those are an
+    /// error. Rows borrow from the reader, so this is not an `Iterator`.
+    pub fn next_row(&mut self) -> Result<Option<Row<'_>>, Error> {
+        let span = loop {
+            let Some(span) = self.reader.advance()? else {
+                return Ok(None);
+            };
+            self.line_num += 1;
+            let line = self.reader.get(&span);
+            if line.is_empty() || self.config.is_ignored(line, &self.header) {
+                continue;
+            }
+            self.offsets.clear();
+            self.offsets.push(usize::MAX);
+            if self.config.rfc4180 {
+                self.offsets.extend(
+                    QuotedDelimiters::new(line, self.config.delimiter).take(self.useful_cols),
+                );
+            } else {
+                self.offsets
+                    .extend(memchr_iter(self.config.delimiter, line).take(self.useful_cols));
+            }
+            self.offsets.push(line.len());
+            let fields = self.offsets.len() - 1;
+            if self
+                .config
+                .check_width(line, fields, self.useful_cols, self.header_len)
+                .map_err(|err| err.at_line(self.line_num))?
+            {
+                break span;
+            }
+        };
+        Ok(Some(Row {
+            line: self.reader.get(&span),
+            offsets: &self.offsets,
+            idx: &self.idx,
+            rfc4180: self.config.rfc4180,
+            null_qty: self.config.null_qty,
+            qty_reduce: self.config.qty_reduce,
+            line_num: self.line_num,
+        }))
+    }
+}
+
+/// A row read by a `RowReader`, which borrows its fields from the reader.
+pub struct Row<'a> {
+    line: &'a [u8],
+    offsets: &'a [usize],
+    idx: &'a ColIndices,
+    rfc4180: bool,
+    null_qty: NullQty,
+    qty_reduce: QtyReduce,
+    line_num: usize,
+}
+
+impl<'a> Row<'a> {
+    #[inline]
+    fn col(&self, i: usize) -> &'a [u8] {
+        let field = get_col(self.line, self.offsets, i);
+        if self.rfc4180 {
+            unquote(field)
+        } else {
+            field
+        }
+    }
+
+    /// The 1-based line number of the row in the input.
+    #[inline]
+    pub fn line_num(&self) -> usize {
+        self.line_num
+    }
+
+    #[inline]
+    pub fn source(&self) -> &'a [u8] {
+        self.col(self.idx.source)
+    }
+
+    #[inline]
+    pub fn bs(&self) -> &'a [u8] {
+        self.col(self.idx.bs)
+    }
+
+    /// The field of the column which rows are grouped by, which is `Prod`
+    /// unless another is given with `--group-by`.
+    #[inline]
+    pub fn prod(&self) -> &'a [u8] {
+        self.col(self.idx.prod)
+    }
+
+    /// The quantity of the row, which is that of the `--qty-col` column, or
+    /// OrdQty, WrkQty, and ExcQty combined by `--qty-reduce`.
+    #[inline]
+    pub fn qty(&self) -> Result<u32, Error> {
+        let parse = |i, name| {
+            parse_qty(self.col(i), name, self.null_qty).map_err(|err| err.at_line(self.line_num))
+        };
+        match self.idx.qty {
+            Some((i, name)) => parse(i, name),
+            None => {
+                let ordqty = parse(self.idx.ordqty, "OrdQty")?;
+                let wrkqty = parse(self.idx.wrkqty, "WrkQty")?;
+                let excqty = parse(self.idx.excqty, "ExcQty")?;
+                Ok(self.qty_reduce.reduce(ordqty, wrkqty, excqty))
+            }
+        }
+    }
+}
+
+#[inline]
+fn calc_custom_read<R: Read, S: Sink>(
+    inputs: &[PathBuf],
+    open: impl Fn(&Path) -> Result<R, Error>,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let mut reader = LineReader::new(open(path)?, config.buf_size);
+
+        let header = reader.read_header(config)?;
+        let (idx, header_len) = ColIndices::from_header(&header, config)?;
+        let idx = headers.check(idx)?;
+        phases::header_parsed();
+        let useful_cols = idx.useful_cols();
+
+        let mut cols_empty: Vec<&'static [u8]> = Vec::with_capacity(useful_cols);
+        let mut key = Vec::new();
+        let mut line_num = config.first_row_line() - 1;
+        while let Some(line) = reader.next_line()? {
+            line_num += 1;
+            if counts.rows() == config.limit {
+                break;
+            }
+            progress.tick(line.len() + 1);
+            if line.is_empty() {
+                counts.blank += 1;
+                continue;
+            }
+            if config.is_ignored(line, &header) {
+                counts.ignored += 1;
+                continue;
+            }
+            let mut cols = cols_empty;
+            split_fields(&mut cols, line, useful_cols, config);
+            if !config
+                .check_width(line, cols.len(), useful_cols, header_len)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.malformed += 1;
+            } else if config
+                .matches_row(&cols, &idx, || line_num)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.processed += 1;
+                if !config.count_only {
+                    let prod = products
+                        .entry_ref(group_key(|i| cols[i], &idx, config, &mut key))
+                        .or_default();
+                    if !prod
+                        .process_row(&cols, &idx, line_num, config)
+                        .map_err(|err| err.at_line(line_num))?
+                    {
+                        counts.add_violation(line_num);
+                    }
+                }
+            } else {
+                counts.skipped += 1;
+            }
+            cols_empty = cols.into_iter().take(0).map(|_| &[][..]).collect();
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Reads the input in blocks of `--buf-size` bytes with one `read` each and
+/// processes the complete lines of each block in place, carrying only the
+/// partial line at its end over to the next, unlike `custom-read`, which
+/// returns lines one at a time.
+#[inline]
+fn calc_block_read<R: Read, S: Sink>(
+    inputs: &[PathBuf],
+    open: impl Fn(&Path) -> Result<R, Error>,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let mut blocks = BlockReader::new(open(path)?, config.buf_size);
+
+        // Blocks hold only whole lines, so the header starts in the block in
+        // which the skipped lines end.
+        let mut remaining = config.skip_rows;
+        let start = loop {
+            let block = blocks.next_block()?.ok_or(HeaderError::Empty)?;
+            match skip_lines_offset(block, remaining) {
+                Ok(start) => break start,
+                Err(lines) => remaining -= lines,
+            }
+        };
+        blocks.rewind();
+        let first = &blocks.next_block()?.unwrap()[start..];
+        let header_end = memchr(b'\n', first).unwrap_or(first.len());
+        let header = trim_eol(&first[..header_end]).to_vec();
+        let (idx, header_len) = ColIndices::from_header(&header, config)?;
+        let idx = headers.check(idx)?;
+        phases::header_parsed();
+        let useful_cols = idx.useful_cols();
+        // The rows of the first block follow the header.
+        let mut skip = if config.no_header {
+            start
+        } else {
+            start + header_end + 1
+        };
+        blocks.rewind();
+
+        let mut cols_empty: Vec<&'static [u8]> = Vec::with_capacity(useful_cols);
+        let mut key = Vec::new();
+        let mut line_num = config.first_row_line() - 1;
+        'blocks: while let Some(block) = blocks.next_block()? {
+            let block = &block[mem::take(&mut skip).min(block.len())..];
+            if block.is_empty() {
+                continue;
+            }
+            let mut cols = cols_empty;
+            // Only the block at the end of the input may be unterminated.
+            let unterminated = !block.ends_with(b"\n");
+            let line_ends = memchr_iter(b'\n', block).chain(unterminated.then_some(block.len()));
+            let mut start = 0;
+            for end in line_ends {
+                let line = trim_eol(&block[start..end]);
+                start = end + 1;
+                line_num += 1;
+                if counts.rows() == config.limit {
+                    break 'blocks;
+                }
+                progress.tick(line.len() + 1);
+                if line.is_empty() {
+                    counts.blank += 1;
+                    continue;
+                }
+                if config.is_ignored(line, &header) {
+                    counts.ignored += 1;
+                    continue;
+                }
+                cols.clear();
+                split_fields(&mut cols, line, useful_cols, config);
+                if !config
+                    .check_width(line, cols.len(), useful_cols, header_len)
+                    .map_err(|err| err.at_line(line_num))?
+                {
+                    counts.malformed += 1;
+                } else if config
+                    .matches_row(&cols, &idx, || line_num)
+                    .map_err(|err| err.at_line(line_num))?
+                {
+                    counts.processed += 1;
+                    if !config.count_only {
+                        let prod = products
+                            .entry_ref(group_key(|i| cols[i], &idx, config, &mut key))
+                            .or_default();
+                        if !prod
+                            .process_row(&cols, &idx, line_num, config)
+                            .map_err(|err| err.at_line(line_num))?
+                        {
+                            counts.add_violation(line_num);
+                        }
+                    }
+                } else {
+                    counts.skipped += 1;
+                }
+            }
+            cols_empty = cols.into_iter().take(0).map(|_| &[][..]).collect();
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Reads an input in blocks which end after their last newline. Each block
+/// is read with one `read` after the partial line carried over from the
+/// last, so lines are only copied when they straddle blocks, and the buffer
+/// only grows for a line longer than a block.
+struct BlockReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    block_size: usize,
+    /// The end of the bytes read into `buf`.
+    len: usize,
+    /// The end of the last block returned, after which is the partial line
+    /// to carry over.
+    end: usize,
+    /// Whether to return the last block again.
+    repeat: bool,
+}
+
+impl<R: Read> BlockReader<R> {
+    fn new(reader: R, block_size: usize) -> Self {
+        BlockReader {
+            reader,
+            buf: vec![0; block_size],
+            block_size,
+            len: 0,
+            end: 0,
+            repeat: false,
+        }
+    }
+
+    /// Reads the next block of complete lines, of which the last is only
+    /// unterminated at the end of the input.
+    fn next_block(&mut self) -> io::Result<Option<&[u8]>> {
+        if mem::take(&mut self.repeat) {
+            return Ok((self.end != 0).then(|| &self.buf[..self.end]));
+        }
+        self.buf.copy_within(self.end..self.len, 0);
+        self.len -= self.end;
+        self.end = 0;
+        loop {
+            if self.buf.len() < self.len + self.block_size {
+                self.buf.resize(self.len + self.block_size, 0);
+            }
+            let n = read_retrying(
+                &mut self.reader,
+                &mut self.buf[self.len..self.len + self.block_size],
+            )?;
+            if n == 0 {
+                self.end = self.len;
+                return Ok((self.len != 0).then(|| &self.buf[..self.len]));
+            }
+            let searched = self.len;
+            self.len += n;
+            if let Some(i) = memrchr(b'\n', &self.buf[searched..self.len]) {
+                self.end = searched + i + 1;
+                return Ok(Some(&self.buf[..self.end]));
+            }
+        }
+    }
+
+    /// Makes the next `next_block` return the last block again.
+    fn rewind(&mut self) {
+        self.repeat = true;
+    }
+}
+
+/// The number of chunks which the pipeline reader may fill ahead of the
+/// parser.
+const PIPELINE_DEPTH: usize = 4;
+
+/// Reads the input on one thread and parses it on another. The reader sends
+/// chunks of whole lines over a bounded channel and the parser returns them
+/// over another for reuse, so that reading overlaps with parsing.
+fn calc_pipeline<R: Read + Send, S: Sink>(
+    inputs: &[PathBuf],
+    open: impl Fn(&Path) -> Result<R, Error>,
+    config: &Config,
+    sink: &mut S,
+) -> Result<(), Error> {
+    let mut products = HashMap::<Box<[u8]>, ProductData>::with_capacity(config.products_hint);
+    let mut counts = RowCounts::default();
+    let mut progress = Progress::new(config, None);
+    let mut headers = HeaderCheck::default();
+    for_each_input(inputs, |path| {
+        let reader = open(path)?;
+        let (full_tx, full_rx) = crossbeam_channel::bounded(PIPELINE_DEPTH);
+        let (empty_tx, empty_rx) = crossbeam_channel::bounded(PIPELINE_DEPTH);
+        thread::scope(|s| {
+            let reader = s.spawn(move || read_line_chunks(reader, full_tx, empty_rx));
+            let res = parse_line_chunks(
+                full_rx,
+                empty_tx,
+                config,
+                &mut headers,
+                &mut products,
+                &mut counts,
+                &mut progress,
+            );
+            // When parsing fails, the receiver has been dropped, so the reader
+            // stops at its next send. A read error is reported first, since
+            // the parser sees it only as the end of the input.
+            let read_res = reader.join().unwrap();
+            read_res?;
+            res
+        })
+    })?;
+    progress.finish();
+    sink.finish(products.iter().map(|(k, v)| (&**k, v)), &counts, config)?;
+    Ok(())
+}
+
+/// Fills buffers from the reader and sends them, each cut after its last
+/// newline, so that no line straddles two chunks. The partial line after the
+/// cut is carried over to the start of the next buffer.
+fn read_line_chunks<R: Read>(
+    mut reader: R,
+    full: Sender<Vec<u8>>,
+    empty: Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(BUF_CAP);
+    loop {
+        let len = buf.len();
+        let n = Read::by_ref(&mut reader)
+            .take(BUF_CAP as u64)
+            .read_to_end(&mut buf)?;
+        if n == 0 {
+            if !buf.is_empty() {
+                let _ = full.send(buf);
+            }
+            return Ok(());
+        }
+        if let Some(i) = memrchr(b'\n', &buf[len..]) {
+            let mut next = empty
+                .try_recv()
+                .unwrap_or_else(|_| Vec::with_capacity(BUF_CAP));
+            next.clear();
+            next.extend_from_slice(&buf[len + i + 1..]);
+            buf.truncate(len + i + 1);
+            if full.send(buf).is_err() {
+                return Ok(());
+            }
+            buf = next;
+        }
+    }
+}
+
+/// Parses the chunks of one input sent by `read_line_chunks` into the
+/// aggregates of all inputs, returning each buffer once it is done with it.
+fn parse_line_chunks(
+    full: Receiver<Vec<u8>>,
+    empty: Sender<Vec<u8>>,
+    config: &Config,
+    headers: &mut HeaderCheck,
+    products: &mut HashMap<Box<[u8]>, ProductData>,
+    counts: &mut RowCounts,
+    progress: &mut Progress,
+) -> Result<(), Error> {
+    // Chunks hold only whole lines, so the header starts in the chunk in
+    // which the skipped lines end.
+    let mut remaining = config.skip_rows;
+    let (first, start) = loop {
+        let chunk = full.recv().map_err(|_| HeaderError::Empty)?;
+        match skip_lines_offset(&chunk, remaining) {
+            Ok(start) => break (chunk, start),
+            Err(lines) => {
+                remaining -= lines;
+                let _ = empty.try_send(chunk);
+            }
+        }
+    };
+    let header_end = start + memchr(b'\n', &first[start..]).unwrap_or(first.len() - start);
+    let header = trim_eol(&first[start..header_end]).to_vec();
+    let (idx, header_len) = ColIndices::from_header(&header, config)?;
+    let idx = headers.check(idx)?;
+    phases::header_parsed();
+    let useful_cols = idx.useful_cols();
+
+    let mut line_num = config.first_row_line() - 1;
+    // Returns whether the row limit has been reached.
+    let mut process_chunk = |chunk: &[u8]| -> Result<bool, Error> {
+        if chunk.is_empty() {
+            return Ok(false);
+        }
+        let mut cols = Vec::with_capacity(useful_cols);
+        let mut key = Vec::new();
+        for line in split_lines(chunk) {
+            line_num += 1;
+            if counts.rows() == config.limit {
+                return Ok(true);
+            }
+            progress.tick(line.len() + 1);
+            if line.is_empty() {
+                counts.blank += 1;
+                continue;
+            }
+            if config.is_ignored(line, &header) {
+                counts.ignored += 1;
+                continue;
+            }
+            cols.clear();
+            split_fields(&mut cols, line, useful_cols, config);
+            if !config
+                .check_width(line, cols.len(), useful_cols, header_len)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.malformed += 1;
+            } else if config
+                .matches_row(&cols, &idx, || line_num)
+                .map_err(|err| err.at_line(line_num))?
+            {
+                counts.processed += 1;
+                if !config.count_only {
+                    let prod = products
+                        .entry_ref(group_key(|i| cols[i], &idx, config, &mut key))
+                        .or_default();
+                    if !prod
+                        .process_row(&cols, &idx, line_num, config)
+                        .map_err(|err| err.at_line(line_num))?
+                    {
+                        counts.add_violation(line_num);
+                    }
+                }
+            } else {
+                counts.skipped += 1;
+            }
+        }
+        Ok(false)
+    };
+
+    // Stopping early drops the receiver, which stops the reader.
+    let body_start = if config.no_header {
+        start
+    } else {
+        (header_end + 1).min(first.len())
+    };
+    if !process_chunk(&first[body_start..])? {
+        let _ = empty.try_send(first);
+        for chunk in full {
+            if process_chunk(&chunk)? {
+                break;
+            }
+            let _ = empty.try_send(chunk);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Header(HeaderError),
+    /// The strategy does not support the input or options.
+    Unsupported(&'static str),
+    /// No strategy has this name.
+    UnknownStrategy(String),
+    /// A field on a 1-based line could not be parsed.
+    Parse {
+        line: usize,
+        err: FieldError,
+    },
+    /// Two strategies aggregated different results for a product.
+    Mismatch {
+        expected: &'static str,
+        actual: &'static str,
+        prod: Vec<u8>,
+    },
+    /// A `--schema` file is invalid on a 1-based line.
+    Schema {
+        line: usize,
+        err: SchemaError,
+    },
+    /// An error in one of several inputs, or in a `--schema` file.
+    Input {
+        path: PathBuf,
+        err: Box<Error>,
+    },
+}
+
+/// The header does not identify the columns required for aggregation.
+#[derive(Debug)]
+pub enum HeaderError {
+    /// The input is empty, so has no header.
+    Empty,
+    /// The header contains none of the tracked columns.
+    Unrecognized,
+    /// The input starts with a UTF-16 byte order mark.
+    Utf16,
+    /// The header lacks these columns.
+    Missing(Vec<&'static str>),
+    /// A column appears twice, at these 0-based indices.
+    Duplicate {
+        name: &'static str,
+        first: usize,
+        second: usize,
+    },
+    /// A column is at a different 0-based index than in the first input.
+    Mismatch {
+        name: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// With `--delimiter-auto`, the header has as many of each of these
+    /// delimiters.
+    AmbiguousDelimiter(Vec<u8>),
+}
+
+/// A line of a `--schema` file cannot be loaded.
+#[derive(Debug)]
+pub enum SchemaError {
+    /// The line is malformed.
+    Syntax(&'static str),
+    /// The value is followed by more than a comment.
+    Trailing(String),
+    /// The key is not one of the schema.
+    UnknownKey(String),
+    /// The key was given on an earlier line.
+    DuplicateKey(String),
+    /// The value of the key has the wrong type or is invalid.
+    InvalidValue(String),
+    /// A column is given by index without `no_header = true`.
+    IndexWithHeader(String),
+    /// `qty_index` is given without a `qty` name for the column.
+    UnnamedQty,
+}
+
+/// A row cannot be aggregated.
+#[derive(Debug)]
+pub enum FieldError {
+    /// A field has an invalid value.
+    Invalid { col: &'static str, value: Box<[u8]> },
+    /// With `--strict`, the row has a different number of fields than the
+    /// header.
+    Width { expected: usize, actual: usize },
+    /// With `--validate-utf8`, a field is not valid UTF-8 from byte
+    /// `valid_up_to`.
+    Utf8 {
+        col: &'static str,
+        value: Box<[u8]>,
+        valid_up_to: usize,
+    },
+}
+
+impl FieldError {
+    #[cold]
+    fn new(col: &'static str, value: &[u8]) -> Self {
+        FieldError::Invalid {
+            col,
+            value: value.into(),
+        }
+    }
+
+    /// Attributes the error to a 1-based line of the input.
+    #[inline]
+    pub fn at_line(self, line: usize) -> Error {
+        Error::Parse { line, err: self }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<HeaderError> for Error {
+    fn from(err: HeaderError) -> Self {
+        Error::Header(err)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Header(err) => write!(f, "{err}"),
+            Error::Unsupported(msg) => f.write_str(msg),
+            Error::UnknownStrategy(name) => write!(
+                f,
+                "unknown strategy `{name}`; valid strategies are {}",
+                STRATEGIES.join(", "),
+            ),
+            Error::Parse { line, err } => write!(f, "line {line}: {err}"),
+            Error::Schema { line, err } => write!(f, "line {line}: {err}"),
+            Error::Mismatch {
+                expected,
+                actual,
+                prod,
+            } => write!(
+                f,
+                "strategies {expected} and {actual} disagree on product {}",
+                String::from_utf8_lossy(prod),
+            ),
+            Error::Input { path, err } => write!(f, "{}: {err}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Syntax(msg) => f.write_str(msg),
+            SchemaError::Trailing(rest) => write!(f, "unexpected `{rest}` after value"),
+            SchemaError::UnknownKey(key) => write!(f, "unknown key `{key}`"),
+            SchemaError::DuplicateKey(key) => write!(f, "duplicate key `{key}`"),
+            SchemaError::InvalidValue(key) => write!(f, "invalid value for `{key}`"),
+            SchemaError::IndexWithHeader(key) => {
+                write!(f, "`{key}` is an index, which requires no_header = true")
+            }
+            SchemaError::UnnamedQty => f.write_str("qty_index requires qty to name the column"),
+        }
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldError::Invalid { col, value } if value.is_empty() => write!(f, "empty {col}"),
+            FieldError::Invalid { col, value } => {
+                write!(f, "invalid {col} \"{}\"", value.escape_ascii())
+            }
+            FieldError::Width { expected, actual } => {
+                write!(f, "expected {expected} columns, but found {actual}")
+            }
+            FieldError::Utf8 {
+                col,
+                value,
+                valid_up_to,
+            } => write!(
+                f,
+                "invalid UTF-8 in {col} \"{}\" at byte {valid_up_to}",
+                value.escape_ascii(),
+            ),
+        }
+    }
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Empty => write!(f, "empty input"),
+            HeaderError::Unrecognized => write!(f, "header has no recognizable columns"),
+            HeaderError::Utf16 => write!(f, "UTF-16 input is not supported"),
+            HeaderError::Missing(missing) => write!(f, "missing columns: {}", missing.join(", ")),
+            HeaderError::Duplicate {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "duplicate column {name} at positions {} and {}",
+                first + 1,
+                second + 1,
+            ),
+            HeaderError::Mismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "column {name} is at position {}, but at {} in the first input",
+                actual + 1,
+                expected + 1,
+            ),
+            HeaderError::AmbiguousDelimiter(tied) => {
+                write!(f, "cannot detect the delimiter between")?;
+                for (i, delim) in tied.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(f, "{sep} '{}'", delim.escape_ascii())?;
+                }
+                write!(f, "; specify --delimiter")
+            }
+        }
+    }
+}
+
+/// The aggregates of a product. It is not `Eq`, as the price sum is a float
+/// which depends on the order that rows were added in; compare with
+/// `approx_eq` instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProductData {
+    pub count: u32,
+    pub buys: u32,
+    pub sells: u32,
+    /// The sum of row quantities, which is wide enough to not overflow for
+    /// liquid products.
+    pub total_qty: u64,
+    /// The sum of squared row quantities, which is exact, so that it does
+    /// not depend on the order rows are added in.
+    pub sq_qty: u128,
+    /// The smallest row quantity, or `u32::MAX` when no rows have been seen.
+    pub min_qty: u32,
+    pub max_qty: u32,
+    /// The total quantity of Buy rows.
+    pub buy_qty: u64,
+    /// The total quantity of Sell rows.
+    pub sell_qty: u64,
+    /// The sum of ExcQty alone, for reconciling against executions.
+    pub exc_total: u64,
+    /// The sum of price times quantity over rows, with `--with-price`.
+    pub px_qty_sum: f64,
+    /// The number of rows with quantities in each power-of-ten bucket, as by
+    /// `qty_bucket`, with `--histogram`.
+    pub qty_hist: [u32; QTY_BUCKETS],
+    /// The line of the first row, or `usize::MAX` when no rows have been
+    /// seen, with `--trace`. Lines are numbered within each input.
+    pub first_line: usize,
+    /// The line of the last row, with `--trace`.
+    pub last_line: usize,
+    /// The earliest timestamp of a row in seconds, or `u64::MAX` when no rows
+    /// have been seen, with `--stats` and a `Time` column.
+    pub min_time: u64,
+    /// The latest timestamp of a row in seconds.
+    pub max_time: u64,
+}
+
+/// The number of `--histogram` buckets: one for 0, then one for each number of
+/// decimal digits of a `u32`.
+pub const QTY_BUCKETS: usize = 11;
+
+/// Returns the `--histogram` bucket of a quantity, which is its number of
+/// decimal digits, so that bucket 3 holds 100 to 999 and bucket 0 holds 0.
+#[inline]
+pub fn qty_bucket(qty: u32) -> usize {
+    qty.checked_ilog10().map_or(0, |digits| digits as usize + 1)
+}
+
+/// Returns the smallest quantity in a `--histogram` bucket.
+#[inline]
+fn bucket_start(bucket: usize) -> u32 {
+    match bucket {
+        0 => 0,
+        _ => 10u32.pow(bucket as u32 - 1),
+    }
+}
+
+impl Default for ProductData {
+    #[inline]
+    fn default() -> Self {
+        ProductData {
+            count: 0,
+            buys: 0,
+            sells: 0,
+            total_qty: 0,
+            sq_qty: 0,
+            min_qty: u32::MAX,
+            max_qty: 0,
+            buy_qty: 0,
+            sell_qty: 0,
+            exc_total: 0,
+            px_qty_sum: 0.0,
+            qty_hist: [0; QTY_BUCKETS],
+            first_line: usize::MAX,
+            last_line: 0,
+            min_time: u64::MAX,
+            max_time: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ColIndices {
+    pub source: usize,
+    pub bs: usize,
+    pub ordqty: usize,
+    pub wrkqty: usize,
+    pub excqty: usize,
+    /// The index of the column which rows are grouped by.
+    pub prod: usize,
+    /// The name of the column which rows are grouped by, which is `Prod`,
+    /// unless another is given with `--group-by`.
+    pub group_by: &'static str,
+    /// The index and name of the `--qty-col` column, if one was requested.
+    pub qty: Option<(usize, &'static str)>,
+    /// The index of the `Time` column, which is only located when filtering
+    /// by time, or with `--stats`, when it is optional.
+    pub time: Option<usize>,
+    /// The index of the `Price` column, which is only located with
+    /// `--with-price`.
+    pub price: Option<usize>,
+}
+
+impl ColIndices {
+    /// Locates the columns in the header and counts its fields. A trailing
+    /// delimiter ends one more, empty, field, as it does in rows, so that a
+    /// file with a trailing delimiter on every line has consistent widths.
+    ///
+    /// With `--no-header`, `header` is the first row instead, so the columns
+    /// are located by `--columns` and only its fields are counted.
+    #[inline]
+    pub fn from_header(header: &[u8], config: &Config) -> Result<(ColIndices, usize), HeaderError> {
+        if config.no_header {
+            let (idx, _) = ColIndices::locate(&config.positional_header(), config)?;
+            return Ok((idx, config.count_fields(header)));
+        }
+        ColIndices::locate(header, config)
+    }
+
+    fn locate(header: &[u8], config: &Config) -> Result<(ColIndices, usize), HeaderError> {
+        let mut source_idx = None;
+        let mut bs_idx = None;
+        let mut ordqty_idx = None;
+        let mut wrkqty_idx = None;
+        let mut excqty_idx = None;
+        let mut prod_idx = None;
+        let mut qty_idx = None;
+        let mut time_idx = None;
+        let mut price_idx = None;
+        let mut cols = 0;
+        let mut fields = Vec::new();
+        split_fields(&mut fields, strip_bom(header)?, usize::MAX, config);
+        // A tracked column may only appear once, so that mis-joined headers
+        // are not silently aggregated from the wrong column.
+        let set = |idx: &mut Option<usize>, i, name| match *idx {
+            Some(first) => Err(HeaderError::Duplicate {
+                name,
+                first,
+                second: i,
+            }),
+            None => {
+                *idx = Some(i);
+                Ok(())
+            }
+        };
+        let filter_time = config.after.is_some() || config.before.is_some();
+        for (i, &col) in fields.iter().enumerate() {
+            if let Some(name) = config.qty_col.filter(|name| config.is_column(col, name)) {
+                set(&mut qty_idx, i, name)?;
+            }
+            if config.is_column(col, config.group_by) {
+                set(&mut prod_idx, i, config.group_by)?;
+            }
+            match config.column_name(col) {
+                b"Source" => set(&mut source_idx, i, "Source")?,
+                b"B/S" => set(&mut bs_idx, i, "B/S")?,
+                b"OrdQty" => set(&mut ordqty_idx, i, "OrdQty")?,
+                b"WrkQty" => set(&mut wrkqty_idx, i, "WrkQty")?,
+                b"ExcQty" => set(&mut excqty_idx, i, "ExcQty")?,
+                b"Time" if filter_time || config.stats => set(&mut time_idx, i, "Time")?,
+                b"Price" if config.with_price => set(&mut price_idx, i, "Price")?,
+                _ => {}
+            }
+            cols += 1;
+        }
+        // A header without any tracked column is likely not a header at all,
+        // or uses another delimiter.
+        let recognized = [
+            source_idx, bs_idx, ordqty_idx, wrkqty_idx, excqty_idx, prod_idx, qty_idx, time_idx,
+            price_idx,
+        ];
+        if recognized.iter().all(Option::is_none) {
+            return Err(HeaderError::Unrecognized);
+        }
+        let mut missing = Vec::new();
+        let mut require = |idx: Option<usize>, name: &'static str| {
+            idx.unwrap_or_else(|| {
+                missing.push(name);
+                0
+            })
+        };
+        let source = require(source_idx, "Source");
+        let bs = require(bs_idx, "B/S");
+        // OrdQty and WrkQty are only needed for the default quantity or to
+        // check invariants, but ExcQty is always summed.
+        let (ordqty, wrkqty) = if config.qty_col.is_none() || config.check_invariants {
+            (require(ordqty_idx, "OrdQty"), require(wrkqty_idx, "WrkQty"))
+        } else {
+            (0, 0)
+        };
+        let qty = config.qty_col.map(|name| (require(qty_idx, name), name));
+        let excqty = require(excqty_idx, "ExcQty");
+        let prod = require(prod_idx, config.group_by);
+        let time = if filter_time {
+            Some(require(time_idx, "Time"))
+        } else {
+            time_idx
+        };
+        let price = config.with_price.then(|| require(price_idx, "Price"));
+        let indices = ColIndices {
+            source,
+            bs,
+            ordqty,
+            wrkqty,
+            excqty,
+            prod,
+            group_by: config.group_by,
+            qty,
+            time,
+            price,
+        };
+        if !missing.is_empty() {
+            return Err(HeaderError::Missing(missing));
+        }
+        Ok((indices, cols))
+    }
+
+    /// The names and indices of the located columns.
+    fn positions(&self) -> impl Iterator<Item = (&'static str, usize)> {
+        [
+            ("Source", self.source),
+            ("B/S", self.bs),
+            ("OrdQty", self.ordqty),
+            ("WrkQty", self.wrkqty),
+            ("ExcQty", self.excqty),
+            (self.group_by, self.prod),
+        ]
+        .into_iter()
+        .chain(self.qty.map(|(i, name)| (name, i)))
+        .chain(self.time.map(|i| ("Time", i)))
+        .chain(self.price.map(|i| ("Price", i)))
+    }
+
+    #[inline]
+    fn useful_cols(&self) -> usize {
+        let qty = self.qty.map_or(0, |(i, _)| i);
+        let time = self.time.unwrap_or(0);
+        let price = self.price.unwrap_or(0);
+        (self.source.max(self.bs))
+            .max(self.ordqty.max(self.wrkqty).max(self.excqty.max(self.prod)))
+            .max(qty.max(time).max(price))
+            + 1
+    }
+}
+
+/// Checks that the columns of every input of a strategy are at the same
+/// indices as in the first, so that rows are not silently misaligned.
+#[derive(Default)]
+struct HeaderCheck {
+    first: Option<ColIndices>,
+}
+
+impl HeaderCheck {
+    fn check(&mut self, idx: ColIndices) -> Result<ColIndices, HeaderError> {
+        let Some(first) = &self.first else {
+            self.first = Some(idx.clone());
+            return Ok(idx);
+        };
+        let mismatch = first
+            .positions()
+            .zip(idx.positions())
+            .find(|((_, expected), (_, actual))| expected != actual);
+        match mismatch {
+            Some(((name, expected), (_, actual))) => Err(HeaderError::Mismatch {
+                name,
+                expected,
+                actual,
+            }),
+            None => Ok(idx),
+        }
+    }
+}
+
+impl ProductData {
+    /// Combines the aggregates of another partition of rows into this one.
+    #[inline]
+    fn merge(&mut self, other: &ProductData) {
+        self.count += other.count;
+        self.buys += other.buys;
+        self.sells += other.sells;
+        self.total_qty += other.total_qty;
+        self.sq_qty += other.sq_qty;
+        self.min_qty = self.min_qty.min(other.min_qty);
+        self.max_qty = self.max_qty.max(other.max_qty);
+        self.buy_qty += other.buy_qty;
+        self.sell_qty += other.sell_qty;
+        self.exc_total += other.exc_total;
+        self.px_qty_sum += other.px_qty_sum;
+        for (bucket, &n) in self.qty_hist.iter_mut().zip(&other.qty_hist) {
+            *bucket += n;
+        }
+        self.first_line = self.first_line.min(other.first_line);
+        self.last_line = self.last_line.max(other.last_line);
+        self.min_time = self.min_time.min(other.min_time);
+        self.max_time = self.max_time.max(other.max_time);
+    }
+
+    /// Whether two aggregates agree, allowing for rounding in the price sum,
+    /// which depends on the order that rows were added in.
+    pub fn approx_eq(&self, other: &ProductData) -> bool {
+        let tolerance = 1e-9 * self.px_qty_sum.abs().max(other.px_qty_sum.abs());
+        (self.px_qty_sum - other.px_qty_sum).abs() <= tolerance
+            && ProductData {
+                px_qty_sum: 0.0,
+                ..self.clone()
+            } == ProductData {
+                px_qty_sum: 0.0,
+                ..other.clone()
+            }
+    }
+
+    /// Aggregates a row, returning whether its quantities are ordered as
+    /// `--check-invariants` expects, which is not checked otherwise.
+    #[inline]
+    fn process_row(
+        &mut self,
+        cols: &[&[u8]],
+        idx: &ColIndices,
+        line_num: usize,
+        config: &Config,
+    ) -> Result<bool, FieldError> {
+        self.process_fields(|i| cols[i], idx, line_num, config)
+    }
+
+    /// Like `process_row`, but gets the fields of a row by index from `col`,
+    /// so that they need not be collected into slices.
+    #[inline]
+    fn process_fields<'a>(
+        &mut self,
+        col: impl Fn(usize) -> &'a [u8],
+        idx: &ColIndices,
+        line_num: usize,
+        config: &Config,
+    ) -> Result<bool, FieldError> {
+        let parse = |i, name| parse_qty(col(i), name, config.null_qty);
+        let (qty, excqty, ordered) = match idx.qty {
+            Some((i, name)) => {
+                let qty = parse(i, name)?;
+                let excqty = parse(idx.excqty, "ExcQty")?;
+                let ordered = !config.check_invariants
+                    || qty_ordered(
+                        parse(idx.ordqty, "OrdQty")?,
+                        parse(idx.wrkqty, "WrkQty")?,
+                        excqty,
+                    );
+                (qty, excqty, ordered)
+            }
+            None => {
+                let ordqty = parse(idx.ordqty, "OrdQty")?;
+                let wrkqty = parse(idx.wrkqty, "WrkQty")?;
+                let excqty = parse(idx.excqty, "ExcQty")?;
+                let ordered = !config.check_invariants || qty_ordered(ordqty, wrkqty, excqty);
+                let qty = config.qty_reduce.reduce(ordqty, wrkqty, excqty);
+                (qty, excqty, ordered)
+            }
+        };
+        let qty = clamp_qty(qty, config);
+        if let Some(i) = idx.price {
+            self.px_qty_sum += parse_price(col(i))? * qty as f64;
+        }
+        self.exc_total += excqty as u64;
+        self.count += 1;
+        let bs = col(idx.bs);
+        if bs == &*config.buy_token {
+            self.buys += 1;
+            self.buy_qty += qty as u64;
+        } else if bs == &*config.sell_token {
+            self.sells += 1;
+            self.sell_qty += qty as u64;
+        }
+        self.add_qty(qty);
+        if config.histogram {
+            self.qty_hist[qty_bucket(qty)] += 1;
+        }
+        if config.trace {
+            self.trace(line_num);
+        }
+        if let Some(i) = idx.time.filter(|_| config.stats) {
+            self.add_time(parse_timestamp(col(i))?);
+        }
+        Ok(ordered)
+    }
+
+    /// Records the timestamp of a row for the order rate of `--stats`.
+    #[inline]
+    fn add_time(&mut self, time: u64) {
+        self.min_time = self.min_time.min(time);
+        self.max_time = self.max_time.max(time);
+    }
+
+    /// Records the line of a row for `--trace`.
+    #[inline]
+    fn trace(&mut self, line_num: usize) {
+        self.first_line = self.first_line.min(line_num);
+        self.last_line = self.last_line.max(line_num);
+    }
+
+    /// Accumulates the quantity of a row.
+    #[inline]
+    fn add_qty(&mut self, qty: u32) {
+        self.total_qty += qty as u64;
+        self.sq_qty += qty as u128 * qty as u128;
+        self.min_qty = self.min_qty.min(qty);
+        self.max_qty = self.max_qty.max(qty);
+    }
+
+    /// The rows per second between the earliest and latest timestamps, or
+    /// `None` without timestamps. Timestamps have a resolution of a second,
+    /// so rows within the same second span one.
+    #[inline]
+    pub fn order_rate(&self) -> Option<f64> {
+        let span = self.max_time.checked_sub(self.min_time)?;
+        Some(self.count as f64 / span.max(1) as f64)
+    }
+
+    /// The line of the first row, or 0 when no rows have been seen.
+    #[inline]
+    pub fn first_line(&self) -> usize {
+        if self.count == 0 {
+            0
+        } else {
+            self.first_line
+        }
+    }
+
+    /// The smallest row quantity, or 0 when no rows have been seen.
+    #[inline]
+    pub fn min_qty(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min_qty
+        }
+    }
+
+    /// The mean row quantity, or 0 when no rows have been seen, rather than
+    /// NaN.
+    #[inline]
+    pub fn avg_qty(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total_qty as f64 / self.count as f64
+        }
+    }
+
+    /// The population standard deviation of row quantities, or 0 when no rows
+    /// have been seen.
+    #[inline]
+    pub fn stddev_qty(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.avg_qty();
+        // Rounding can make E[x^2] - E[x]^2 slightly negative.
+        (self.sq_qty as f64 / self.count as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+
+    /// The percentage of buy and sell rows which are buys, or 0 when no rows
+    /// have been either.
+    #[inline]
+    pub fn buy_pct(&self) -> f64 {
+        let classified = self.buys + self.sells;
+        if classified == 0 {
+            0.0
+        } else {
+            self.buys as f64 / classified as f64 * 100.0
+        }
+    }
+
+    /// The volume-weighted average price, or 0 when no quantity has been seen.
+    #[inline]
+    pub fn vwap(&self) -> f64 {
+        if self.total_qty == 0 {
+            0.0
+        } else {
+            self.px_qty_sum / self.total_qty as f64
+        }
+    }
+
+    #[inline]
+    fn fmt<W: Write>(
+        &self,
+        w: &mut W,
+        prod: &[u8],
+        side: Option<&[u8]>,
+        fields: &[OutputField],
+        precision: Option<usize>,
+    ) -> io::Result<()> {
+        // The decimal places of each float metric, unless set by `--precision`.
+        let prec = |default| precision.unwrap_or(default);
+        w.write_all(prod)?;
+        if let Some(side) = side {
+            w.write_all(b" ")?;
+            w.write_all(side)?;
+        }
+        for &field in fields {
+            match field {
+                OutputField::Count => write!(w, " cnt={:4}", self.count)?,
+                OutputField::Buys => write!(w, " buy={:4}", self.buys)?,
+                OutputField::Sells => write!(w, " sell={:4}", self.sells)?,
+                OutputField::AvgQty => {
+                    let prec = prec(2);
+                    write!(
+                        w,
+                        " avg qty={:width$.prec$}",
+                        self.avg_qty(),
+                        width = prec + 4
+                    )?
+                }
+                OutputField::MinQty => write!(w, " min={:4}", self.min_qty())?,
+                OutputField::MaxQty => write!(w, " max={:4}", self.max_qty)?,
+                OutputField::BuyQty => write!(w, " buy qty={:6}", self.buy_qty)?,
+                OutputField::SellQty => write!(w, " sell qty={:6}", self.sell_qty)?,
+                OutputField::ExcTotal => write!(w, " exc qty={:6}", self.exc_total)?,
+                OutputField::StddevQty => {
+                    let prec = prec(2);
+                    write!(
+                        w,
+                        " stddev qty={:width$.prec$}",
+                        self.stddev_qty(),
+                        width = prec + 4
+                    )?
+                }
+                OutputField::BuyPct => {
+                    let prec = prec(1);
+                    write!(w, " buy%={:width$.prec$}", self.buy_pct(), width = prec + 4)?
+                }
+                OutputField::Rate => {
+                    if let Some(rate) = self.order_rate() {
+                        write!(w, " rate={rate:.prec$} rows/s", prec = prec(3))?;
+                    }
+                }
+                OutputField::Vwap => write!(w, " vwap={:.prec$}", self.vwap(), prec = prec(4))?,
+                OutputField::QtyHist => {
+                    w.write_all(b" hist=[")?;
+                    let buckets = self.qty_hist.iter().enumerate().filter(|&(_, &n)| n != 0);
+                    for (i, (bucket, n)) in buckets.enumerate() {
+                        if i != 0 {
+                            w.write_all(b" ")?;
+                        }
+                        match bucket {
+                            0 => write!(w, "0:{n}")?,
+                            _ => {
+                                let end = bucket_start(bucket)
+                                    .checked_mul(10)
+                                    .map_or(u32::MAX, |end| end - 1);
+                                write!(w, "{}-{end}:{n}", bucket_start(bucket))?;
+                            }
+                        }
+                    }
+                    w.write_all(b"]")?;
+                }
+                OutputField::FirstLine => write!(w, " first_line={}", self.first_line())?,
+                OutputField::LastLine => write!(w, " last_line={}", self.last_line)?,
+            }
+        }
+        writeln!(w)
+    }
+
+    #[inline]
+    fn fmt_json<W: Write>(
+        &self,
+        w: &mut W,
+        prod: &[u8],
+        side: Option<&[u8]>,
+        fields: &[OutputField],
+        precision: Option<usize>,
+    ) -> io::Result<()> {
+        // The decimal places of each float metric, unless set by `--precision`.
+        let prec = |default| precision.unwrap_or(default);
+        w.write_all(b"{\"prod\":")?;
+        write_json_str(w, prod)?;
+        if let Some(side) = side {
+            w.write_all(b",\"side\":")?;
+            write_json_str(w, side)?;
+        }
+        for &field in fields {
+            let name = field.name();
+            match field {
+                OutputField::Count => write!(w, ",\"{name}\":{}", self.count)?,
+                OutputField::Buys => write!(w, ",\"{name}\":{}", self.buys)?,
+                OutputField::Sells => write!(w, ",\"{name}\":{}", self.sells)?,
+                OutputField::AvgQty => {
+                    write!(w, ",\"{name}\":{:.prec$}", self.avg_qty(), prec = prec(2))?
+                }
+                OutputField::MinQty => write!(w, ",\"{name}\":{}", self.min_qty())?,
+                OutputField::MaxQty => write!(w, ",\"{name}\":{}", self.max_qty)?,
+                OutputField::BuyQty => write!(w, ",\"{name}\":{}", self.buy_qty)?,
+                OutputField::SellQty => write!(w, ",\"{name}\":{}", self.sell_qty)?,
+                OutputField::ExcTotal => write!(w, ",\"{name}\":{}", self.exc_total)?,
+                OutputField::StddevQty => write!(
+                    w,
+                    ",\"{name}\":{:.prec$}",
+                    self.stddev_qty(),
+                    prec = prec(2)
+                )?,
+                OutputField::BuyPct => {
+                    write!(w, ",\"{name}\":{:.prec$}", self.buy_pct(), prec = prec(1))?
+                }
+                OutputField::Rate => {
+                    if let Some(rate) = self.order_rate() {
+                        write!(w, ",\"{name}\":{rate:.prec$}", prec = prec(3))?;
+                    }
+                }
+                OutputField::Vwap => {
+                    write!(w, ",\"{name}\":{:.prec$}", self.vwap(), prec = prec(4))?
+                }
+                OutputField::QtyHist => {
+                    write!(w, ",\"{name}\":[")?;
+                    for (bucket, n) in self.qty_hist.iter().enumerate() {
+                        write!(w, "{}{n}", if bucket == 0 { "" } else { "," })?;
+                    }
+                    w.write_all(b"]")?;
+                }
+                OutputField::FirstLine => write!(w, ",\"{name}\":{}", self.first_line())?,
+                OutputField::LastLine => write!(w, ",\"{name}\":{}", self.last_line)?,
+            }
+        }
+        w.write_all(b"}")
+    }
+
+    #[inline]
+    fn fmt_csv<W: Write>(
+        &self,
+        w: &mut W,
+        prod: &[u8],
+        side: Option<&[u8]>,
+        fields: &[OutputField],
+        precision: Option<usize>,
+    ) -> io::Result<()> {
+        // The decimal places of each float metric, unless set by `--precision`.
+        let prec = |default| precision.unwrap_or(default);
+        write_csv_field(w, prod)?;
+        if let Some(side) = side {
+            w.write_all(b",")?;
+            write_csv_field(w, side)?;
+        }
+        for &field in fields {
+            match field {
+                OutputField::Count => write!(w, ",{}", self.count)?,
+                OutputField::Buys => write!(w, ",{}", self.buys)?,
+                OutputField::Sells => write!(w, ",{}", self.sells)?,
+                OutputField::AvgQty => write!(w, ",{:.prec$}", self.avg_qty(), prec = prec(2))?,
+                OutputField::MinQty => write!(w, ",{}", self.min_qty())?,
+                OutputField::MaxQty => write!(w, ",{}", self.max_qty)?,
+                OutputField::BuyQty => write!(w, ",{}", self.buy_qty)?,
+                OutputField::SellQty => write!(w, ",{}", self.sell_qty)?,
+                OutputField::ExcTotal => write!(w, ",{}", self.exc_total)?,
+                OutputField::StddevQty => {
+                    write!(w, ",{:.prec$}", self.stddev_qty(), prec = prec(2))?
+                }
+                OutputField::BuyPct => write!(w, ",{:.prec$}", self.buy_pct(), prec = prec(1))?,
+                // An unknown rate is left empty, to keep the columns aligned.
+                OutputField::Rate => match self.order_rate() {
+                    Some(rate) => write!(w, ",{rate:.prec$}", prec = prec(3))?,
+                    None => w.write_all(b",")?,
+                },
+                OutputField::Vwap => write!(w, ",{:.prec$}", self.vwap(), prec = prec(4))?,
+                OutputField::QtyHist => {
+                    for n in &self.qty_hist {
+                        write!(w, ",{n}")?;
+                    }
+                }
+                OutputField::FirstLine => write!(w, ",{}", self.first_line())?,
+                OutputField::LastLine => write!(w, ",{}", self.last_line)?,
+            }
+        }
+        writeln!(w)
+    }
+
+    /// Writes a record of `--format bin`, as read by `read_bin_product`.
+    #[inline]
+    fn fmt_bin<W: Write>(&self, w: &mut W, prod: &[u8], side: Option<&[u8]>) -> io::Result<()> {
+        let len = prod.len() + side.map_or(0, |side| side.len() + 1);
+        let len = u32::try_from(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "product is too long for --format bin",
+            )
+        })?;
+        w.write_all(&len.to_le_bytes())?;
+        w.write_all(prod)?;
+        if let Some(side) = side {
+            w.write_all(b" ")?;
+            w.write_all(side)?;
+        }
+        w.write_all(&self.count.to_le_bytes())?;
+        w.write_all(&self.buys.to_le_bytes())?;
+        w.write_all(&self.sells.to_le_bytes())?;
+        w.write_all(&self.total_qty.to_le_bytes())?;
+        w.write_all(&self.max_qty.to_le_bytes())
+    }
+}
+
+/// Writes bytes as a CSV field, which is quoted as in RFC 4180 when it
+/// contains a comma, quote, or line break.
+fn write_csv_field<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    if !s.iter().any(|&b| matches!(b, b',' | b'"' | b'\n' | b'\r')) {
+        return w.write_all(s);
+    }
+    w.write_all(b"\"")?;
+    for field in s.split(|&b| b == b'"') {
+        if field.as_ptr() != s.as_ptr() {
+            w.write_all(b"\"\"")?;
+        }
+        w.write_all(field)?;
+    }
+    w.write_all(b"\"")
+}
+
+/// Escapes control characters and invalid UTF-8 in a product key for
+/// `--safe-keys`, as `\n`, `\u{1b}`, or `\xff`, so that it cannot corrupt a
+/// terminal and distinct keys remain distinct in JSON.
+fn safe_key(prod: &[u8]) -> Cow<'_, [u8]> {
+    match str::from_utf8(prod) {
+        Ok(s) if !s.chars().any(char::is_control) => Cow::Borrowed(prod),
+        _ => {
+            let mut key = String::with_capacity(prod.len() + 4);
+            for chunk in prod.utf8_chunks() {
+                for ch in chunk.valid().chars() {
+                    if ch.is_control() {
+                        key.extend(ch.escape_debug());
+                    } else {
+                        key.push(ch);
+                    }
+                }
+                for b in chunk.invalid() {
+                    key.push_str(&format!("\\x{b:02x}"));
+                }
+            }
+            Cow::Owned(key.into_bytes())
+        }
+    }
+}
+
+/// Writes bytes as a JSON string, replacing invalid UTF-8 with U+FFFD.
+fn write_json_str<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for ch in String::from_utf8_lossy(s).chars() {
+        match ch {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            '\r' => w.write_all(b"\\r")?,
+            '\t' => w.write_all(b"\\t")?,
+            ch if ch < ' ' => write!(w, "\\u{:04x}", ch as u32)?,
+            ch => w.write_all(ch.encode_utf8(&mut [0; 4]).as_bytes())?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
+#[inline]
+fn print_products<'a, W: Write>(
+    w: &mut W,
+    iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+    counts: &RowCounts,
+    config: &Config,
+) -> io::Result<()> {
+    if config.count_only {
+        return writeln!(w, "{}", counts.processed);
+    }
+    // Keep structured output parseable by reporting the counts separately.
+    match config.format {
+        Format::Text => writeln!(w, "{counts}")?,
+        Format::Json | Format::Csv | Format::Bin => eprintln!("{counts}"),
+    }
+    // Totals are over every product, including those cut by `--top`.
+    let mut total = ProductData::default();
+    let iter = iter.inspect(|&(_, data)| total.merge(data));
+    if config.sort == SortOrder::None && config.top.is_none() {
+        write_products(w, iter, config)?;
+    } else {
+        write_sorted_products(w, iter, config)?;
+    }
+    // Structured output holds only products, so the totals can be recomputed
+    // from it instead.
+    if config.format == Format::Text {
+        total.fmt(w, b"TOTAL", None, &config.output_fields(), config.precision)?;
+    }
+    Ok(())
+}
+
+/// Writes each product to `<prod>.txt` in the `--split-output` directory, in
+/// the format it would be printed in with the others, with the counts
+/// reported to stderr. Files are named by `file_name`, and two products
+/// which would be written to the same file are an error, before any file is
+/// written.
+fn split_products<'a>(
+    iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+    counts: &RowCounts,
+    config: &Config,
+) -> io::Result<()> {
+    let dir = config.split_output.as_deref().unwrap();
+    eprintln!("{counts}");
+    let products: Vec<_> = if config.top.is_some() {
+        sorted_products(iter, config)
+    } else {
+        iter.collect()
+    };
+    let files: Vec<_> = products.iter().map(|&(prod, _)| file_name(prod)).collect();
+    let mut names = HashMap::<&str, &[u8]>::with_capacity(products.len());
+    for (name, &(prod, _)) in files.iter().zip(&products) {
+        if let Some(other) = names.insert(name, prod) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "products {} and {} would both be written to {name}",
+                    other.escape_ascii(),
+                    prod.escape_ascii(),
+                ),
+            ));
+        }
+    }
+    fs::create_dir_all(dir)?;
+    for (name, product) in files.iter().zip(products) {
+        let mut w = BufWriter::new(File::create(dir.join(name))?);
+        write_products(&mut w, iter::once(product), config)?;
+        w.flush()?;
+    }
+    Ok(())
+}
+
+/// The name of the `--split-output` file of a product, with path separators
+/// and control characters replaced with `_`, and invalid UTF-8 with U+FFFD,
+/// so that it stays within the directory.
+fn file_name(prod: &[u8]) -> String {
+    let mut name: String = String::from_utf8_lossy(prod)
+        .chars()
+        .map(|ch| match ch {
+            '/' | '\\' => '_',
+            ch if ch.is_control() => '_',
+            ch => ch,
+        })
+        .collect();
+    if name.is_empty() || name == "." || name == ".." {
+        name.insert(0, '_');
+    }
+    name.push_str(".txt");
+    name
+}
+
+/// Writes the products ordered by `--top` and `--sort`.
+fn write_sorted_products<'a, W: Write>(
+    w: &mut W,
+    iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+    config: &Config,
+) -> io::Result<()> {
+    write_products(w, sorted_products(iter, config).into_iter(), config)
+}
+
+/// Collects the products ordered by `--top` and `--sort`.
+fn sorted_products<'a>(
+    iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+    config: &Config,
+) -> Vec<(&'a [u8], &'a ProductData)> {
+    let mut products = iter.collect::<Vec<_>>();
+    if let Some(n) = config.top {
+        // Rank descending by the chosen metric, with ties by symbol.
+        let rank = |(prod1, data1): &(&[u8], &ProductData),
+                    (prod2, data2): &(&[u8], &ProductData)| {
+            let metric = |data: &ProductData| match config.top_by {
+                TopBy::Count => data.count as u64,
+                TopBy::Qty => data.total_qty,
+            };
+            metric(data2)
+                .cmp(&metric(data1))
+                .then_with(|| prod1.cmp(prod2))
+        };
+        if n < products.len() {
+            if n > 0 {
+                products.select_nth_unstable_by(n - 1, rank);
+            }
+            products.truncate(n);
+        }
+        if config.sort == SortOrder::None {
+            products.sort_unstable_by(rank);
+        }
+    }
+    match config.sort {
+        SortOrder::None => {}
+        SortOrder::Symbol => products.sort_unstable_by_key(|&(prod, _)| prod),
+        SortOrder::Count => products.sort_unstable_by(|(prod1, data1), (prod2, data2)| {
+            data2.count.cmp(&data1.count).then_with(|| prod1.cmp(prod2))
+        }),
+    }
+    products
+}
+
+#[inline]
+fn write_products<'a, W: Write>(
+    w: &mut W,
+    iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+    config: &Config,
+) -> io::Result<()> {
+    // Keys are escaped as they are written, so are sorted by their bytes.
+    let escape = |key| {
+        if config.safe_keys {
+            safe_key(key)
+        } else {
+            Cow::Borrowed(key)
+        }
+    };
+    let iter = iter.map(|(key, data)| {
+        let (prod, side) = split_group_key(key, config);
+        (escape(prod), side.map(escape), data)
+    });
+    let fields = config.output_fields();
+    match config.format {
+        Format::Text => {
+            for (prod, side, data) in iter {
+                data.fmt(w, &prod, side.as_deref(), &fields, config.precision)?;
+            }
+            Ok(())
+        }
+        Format::Json => {
+            w.write_all(b"[")?;
+            for (i, (prod, side, data)) in iter.enumerate() {
+                w.write_all(if i == 0 { b"\n  " } else { b",\n  " })?;
+                data.fmt_json(w, &prod, side.as_deref(), &fields, config.precision)?;
+            }
+            w.write_all(b"\n]\n")
+        }
+        Format::Csv => {
+            w.write_all(b"Prod")?;
+            if config.group_by_side {
+                w.write_all(b",Side")?;
+            }
+            for &field in fields.iter() {
+                if field == OutputField::QtyHist {
+                    for bucket in 0..QTY_BUCKETS {
+                        write!(w, ",{}{}", field.csv_name(), bucket_start(bucket))?;
+                    }
+                } else {
+                    write!(w, ",{}", field.csv_name())?;
+                }
+            }
+            w.write_all(b"\n")?;
+            for (prod, side, data) in iter {
+                data.fmt_csv(w, &prod, side.as_deref(), &fields, config.precision)?;
+            }
+            Ok(())
+        }
+        Format::Bin => {
+            for (prod, side, data) in iter {
+                data.fmt_bin(w, &prod, side.as_deref())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A product as written by `--format bin`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinProduct {
+    /// The product, followed by a space and B/S with `--group-by <name>,bs`.
+    pub prod: Vec<u8>,
+    pub count: u32,
+    pub buys: u32,
+    pub sells: u32,
+    pub total_qty: u64,
+    pub max_qty: u32,
+}
+
+/// Reads the next product written by `--format bin`, or `None` at the end of
+/// the input. Each record is the length of the product as a little-endian
+/// `u32`, its bytes, then `count`, `buys`, `sells` as `u32`, `total_qty` as
+/// `u64`, and `max_qty` as `u32`, all little-endian.
+pub fn read_bin_product<R: Read>(r: &mut R) -> io::Result<Option<BinProduct>> {
+    let mut len = [0; 4];
+    // The input may only end between records.
+    if read_retrying(r, &mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    r.read_exact(&mut len[1..])?;
+    let mut prod = vec![0; u32::from_le_bytes(len) as usize];
+    r.read_exact(&mut prod)?;
+    let mut fixed = [0; 24];
+    r.read_exact(&mut fixed)?;
+    let u32_at = |i: usize| u32::from_le_bytes(fixed[i..i + 4].try_into().unwrap());
+    Ok(Some(BinProduct {
+        prod,
+        count: u32_at(0),
+        buys: u32_at(4),
+        sells: u32_at(8),
+        total_qty: u64::from_le_bytes(fixed[12..20].try_into().unwrap()),
+        max_qty: u32_at(20),
+    }))
+}
@@ -1,366 +1,948 @@
-use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::Path;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::mem::MaybeUninit;
+use std::path::PathBuf;
 use std::process;
-use std::str;
-use std::time::Instant;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use memchr::{memchr, memchr_iter};
-use memmap2::Mmap;
+use cliff_stock_bench::phases;
+use cliff_stock_bench::{
+    builtin_column, filter, inspect, is_stdin, line_stats, load_schema, parse_time, run_strategy,
+    source_summary, verify, BenchFormat, Collect, Config, Error, Expected, Format, MaxQtyPolicy,
+    NullQty, OutputField, Print, ProductData, QtyReduce, RowCounts, Sink, SortOrder, TopBy,
+    STRATEGIES,
+};
 
-fn main() {
-    let mut args = env::args_os();
-    if args.len() != 3 {
-        eprint!(
-            "\
-Usage: cargo run --release <data> <strategy>
-
-Strategies:
-    fulltext
-    memmap-ref
-    memmap-clone
-    read
-    read-memmap
-    read-custom
-"
-        );
-        process::exit(2);
-    }
-    _ = args.next();
-    let filename = args.next().unwrap();
-    let strategy = args.next().unwrap();
-    let start = Instant::now();
-    match strategy.to_str() {
-        Some("fulltext") => calc_key_ref(fs::read(filename).unwrap()),
-        Some("memmap-ref") => calc_key_ref(memmap(filename)),
-        Some("memmap-clone") => calc_key_clone(memmap(filename)),
-        Some("read") => calc_read(File::open(filename).unwrap()),
-        Some("read-memmap") => calc_read(&*memmap(filename)),
-        Some("read-memchr") => calc_read_memchr(File::open(filename).unwrap()),
-        Some("custom-read") => calc_custom_read(File::open(filename).unwrap()).unwrap(),
-        _ => panic!("Unknown strategy"),
-    }
-    println!("Elapsed: {:?}", start.elapsed());
-}
+mod mem;
 
-#[inline]
-fn memmap<P: AsRef<Path>>(path: P) -> Mmap {
-    let file = File::open(path).unwrap();
-    unsafe { Mmap::map(&file).unwrap() }
-}
-
-#[inline]
-fn calc_key_ref<T: AsRef<[u8]>>(text: T) {
-    let mut lines = text.as_ref().split(|&b| b == b'\n');
-    let (idx, _header_len) = ColIndices::from_header(lines.next().unwrap());
-    let useful_cols = idx.useful_cols();
-
-    let mut products = HashMap::<&[u8], ProductData>::new();
-    let mut cols = Vec::with_capacity(useful_cols);
-    for line in lines {
-        if line.len() == 0 {
-            continue;
+fn main() {
+    let (inputs, strategy, config) = parse_args();
+    if config.mem {
+        mem::enable();
+    }
+    if config.runs > 1 && inputs.iter().any(|path| is_stdin(path)) {
+        eprintln!("error: stdin can only be read once, so cannot be used with --runs");
+        process::exit(1);
+    }
+    if let Some(threads) = config.threads {
+        // Built once up front, so that spawning the threads is not timed.
+        let res = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global();
+        if let Err(err) = res {
+            eprintln!("error: {err}");
+            process::exit(1);
         }
-        cols.clear();
-        cols.extend(line.split(|&b| b == b',').take(useful_cols));
-        if cols[idx.source] == b"ToClnt" {
-            let prod = products.entry(cols[idx.prod]).or_default();
-            prod.process_row(&cols, &idx);
+    }
+    if strategy == "inspect" {
+        if let Err(err) = inspect(&inputs, &config) {
+            eprintln!("error: {err}");
+            process::exit(1);
         }
+        return;
     }
-    print_products(products.iter().map(|(k, v)| (*k, v)));
-}
-
-#[inline]
-fn calc_key_clone<T: AsRef<[u8]>>(text: T) {
-    let mut lines = text.as_ref().split(|&b| b == b'\n');
-    let (idx, _header_len) = ColIndices::from_header(lines.next().unwrap());
-    let useful_cols = idx.useful_cols();
-
-    let mut products = hashbrown::HashMap::<Box<[u8]>, ProductData>::new();
-    let mut cols = Vec::with_capacity(useful_cols);
-    for line in lines {
-        if line.len() == 0 {
-            continue;
+    if config.line_stats {
+        if let Err(err) = line_stats(&inputs, &config) {
+            eprintln!("error: {err}");
+            process::exit(1);
+        }
+        return;
+    }
+    if config.source_summary {
+        match source_summary(&inputs, &config) {
+            Ok(counts) => eprintln!("{counts}"),
+            Err(err) => {
+                eprintln!("error: {err}");
+                process::exit(1);
+            }
         }
-        cols.clear();
-        cols.extend(line.split(|&b| b == b',').take(useful_cols));
-        if cols[idx.source] == b"ToClnt" {
-            let prod = products.entry_ref(cols[idx.prod]).or_default();
-            prod.process_row(&cols, &idx);
+        return;
+    }
+    if strategy == "filter" {
+        match filter(&inputs, &config) {
+            Ok(counts) => eprintln!("{counts}"),
+            Err(err) => {
+                eprintln!("error: {err}");
+                process::exit(1);
+            }
         }
+        return;
     }
-    print_products(products.iter().map(|(k, v)| (&**k, v)));
-}
-
-#[inline]
-fn calc_read<R: Read>(reader: R) {
-    let mut reader = BufReader::new(reader);
-
-    let mut line = Vec::new();
-    reader.read_until(b'\n', &mut line).unwrap();
-    let (idx, _header_len) = ColIndices::from_header(&line);
-    let useful_cols = idx.useful_cols();
-
-    let mut products = hashbrown::HashMap::<Box<[u8]>, ProductData>::new();
-    let mut cols_empty: Vec<&'static [u8]> = Vec::with_capacity(useful_cols);
-    loop {
-        line.clear();
-        if reader.read_until(b'\n', &mut line).unwrap() == 0 {
-            break;
+    if config.warm_cache {
+        if let Err(err) = warm_cache(&inputs) {
+            eprintln!("error: {err}");
+            process::exit(1);
         }
-        if line.len() == 0 {
-            continue;
+    }
+    // The pseudo-strategies report only their total time.
+    let name = strategy.to_str().unwrap_or_default();
+    let res = match strategy.to_str() {
+        Some(mode @ ("verify" | "bench-all")) => {
+            mem::reset();
+            phases::reset();
+            let mut times = Times::default();
+            let res = times.time(|| {
+                if mode == "verify" {
+                    verify(&inputs, &config)
+                } else {
+                    bench_all(&inputs, &config)
+                }
+            });
+            res.map(|()| (times, false))
         }
-        let mut cols = cols_empty;
-        cols.extend(line.split(|&b| b == b',').take(useful_cols));
-        if cols[idx.source] == b"ToClnt" {
-            let prod = products.entry_ref(cols[idx.prod]).or_default();
-            prod.process_row(&cols, &idx);
+        Some(strategy) => {
+            time_runs(strategy, &inputs, &config, &mut Print).map(|times| (times, config.runs > 1))
         }
-        cols_empty = cols.into_iter().take(0).map(|_| &[][..]).collect();
+        None => usage(),
+    };
+    let (times, warmup) = res.unwrap_or_else(|err| {
+        eprintln!("error: {err}");
+        process::exit(1);
+    });
+    // Keep stdout machine-readable when structured output is requested, and
+    // JSON timings apart from any products printed to stdout.
+    let to_stdout = match config.bench_format {
+        BenchFormat::Text => config.format == Format::Text,
+        BenchFormat::Json => config.quiet || config.output.is_some(),
+    };
+    let res = if to_stdout {
+        print_summary(&mut io::stdout().lock(), name, &times, warmup, &config)
+    } else {
+        print_summary(&mut io::stderr().lock(), name, &times, warmup, &config)
+    };
+    if let Err(err) = res {
+        eprintln!("error: {err}");
+        process::exit(1);
     }
-    print_products(products.iter().map(|(k, v)| (&**k, v)));
 }
 
-#[inline]
-fn calc_read_memchr<R: Read>(reader: R) {
-    let mut reader = BufReader::new(reader);
-
-    let mut line = Vec::new();
-    reader.read_until(b'\n', &mut line).unwrap();
-    let (idx, _header_len) = ColIndices::from_header(&line);
-    let useful_cols = idx.useful_cols();
-
-    let mut products = hashbrown::HashMap::<Box<[u8]>, ProductData>::new();
-    let mut cols: Vec<usize> = Vec::with_capacity(useful_cols);
-    loop {
-        line.clear();
-        if reader.read_until(b'\n', &mut line).unwrap() == 0 {
-            break;
-        }
-        if line.len() == 0 {
-            continue;
-        }
-        cols.clear();
-        cols.push(usize::MAX);
-        cols.extend(memchr_iter(b',', &line).take(useful_cols));
-        cols.push(line.len());
-        if get_col(&line, &cols, idx.source) == b"ToClnt" {
-            #[inline]
-            fn parse_u32(s: &[u8]) -> u32 {
-                // SAFETY: The grammar for u32::from_str_radix is all ASCII and it
-                // parses as bytes, rejecting any non-ASCII sequences, so it handles
-                // invalid UTF-8 safely.
-                let s = unsafe { str::from_utf8_unchecked(s) };
-                s.parse().unwrap()
-            }
+/// Reads every input once and discards it, so that the page cache holds it
+/// for `--warm-cache`.
+fn warm_cache(inputs: &[PathBuf]) -> io::Result<()> {
+    for path in inputs.iter().filter(|path| !is_stdin(path)) {
+        io::copy(&mut File::open(path)?, &mut io::sink())?;
+    }
+    Ok(())
+}
 
-            let prod = products
-                .entry_ref(get_col(&line, &cols, idx.prod))
-                .or_default();
-            prod.count += 1;
-            match get_col(&line, &cols, idx.bs) {
-                b"Buy" => prod.buys += 1,
-                b"Sell" => prod.sells += 1,
-                _ => {}
-            }
-            let ordqty = parse_u32(get_col(&line, &cols, idx.ordqty));
-            let wrkqty = parse_u32(get_col(&line, &cols, idx.wrkqty));
-            let excqty = parse_u32(get_col(&line, &cols, idx.excqty));
-            prod.total_qty += ordqty.max(wrkqty.max(excqty));
+/// The elapsed wall-clock and CPU times of each run.
+#[derive(Default)]
+struct Times {
+    wall: Vec<Duration>,
+    /// The CPU time summed over every thread, which exceeds the wall-clock
+    /// time when threads run in parallel, or empty where it is not measured.
+    cpu: Vec<Duration>,
+    /// The rows read by each run, for throughput, or `None` for the
+    /// pseudo-strategies.
+    rows: Option<usize>,
+    /// The size of the inputs, or `None` when reading stdin.
+    bytes: Option<u64>,
+}
+
+impl Times {
+    /// Runs and times `f`.
+    fn time<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let cpu_start = cpu_time();
+        let start = Instant::now();
+        let res = f();
+        self.wall.push(start.elapsed());
+        if let (Some(start), Some(end)) = (cpu_start, cpu_time()) {
+            self.cpu.push(end.saturating_sub(start));
         }
+        res
     }
-    print_products(products.iter().map(|(k, v)| (&**k, v)));
-}
 
-#[inline]
-fn get_col<'a>(line: &'a [u8], cols: &[usize], col: usize) -> &'a [u8] {
-    &line[cols[col].wrapping_add(1)..cols[col + 1]]
+    /// The wall-clock time of the last run.
+    fn last(&self) -> Duration {
+        self.wall[self.wall.len() - 1]
+    }
+
+    /// The megabytes and rows read per second in the median run.
+    fn throughput(&self) -> Option<(Option<f64>, f64)> {
+        let secs = Summary::new(&self.wall).median.as_secs_f64();
+        let rows = self.rows? as f64 / secs;
+        Some((self.bytes.map(|bytes| bytes as f64 / 1e6 / secs), rows))
+    }
 }
 
-struct LineReader<R> {
-    reader: R,
-    buf: Box<[u8; BUF_CAP]>,
-    len: usize,
-    cur: usize,
-    line: Vec<u8>,
+/// The total size of the inputs, or `None` when reading stdin. Compressed
+/// inputs count their compressed size.
+fn input_bytes(inputs: &[PathBuf]) -> Option<u64> {
+    inputs
+        .iter()
+        .map(|path| {
+            if is_stdin(path) {
+                None
+            } else {
+                fs::metadata(path).ok().map(|metadata| metadata.len())
+            }
+        })
+        .sum()
 }
 
-const BUF_CAP: usize = 32 * 1024;
+/// Passes the products on to another sink, keeping the number of rows read.
+struct CountRows<'a, S> {
+    sink: &'a mut S,
+    rows: usize,
+}
 
-impl<R: Read> LineReader<R> {
-    fn new(reader: R) -> Self {
-        LineReader {
-            reader,
-            buf: vec![0; BUF_CAP].into_boxed_slice().try_into().unwrap(),
-            len: 0,
-            cur: 0,
-            line: Vec::with_capacity(1024),
-        }
+impl<S: Sink> Sink for CountRows<'_, S> {
+    fn finish<'a>(
+        &mut self,
+        iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+        counts: &RowCounts,
+        config: &Config,
+    ) -> io::Result<()> {
+        self.rows = counts.rows();
+        self.sink.finish(iter, counts, config)
     }
+}
 
-    fn next_line(&mut self) -> io::Result<Option<&[u8]>> {
-        self.line.clear();
-        loop {
-            match memchr(b'\n', &self.buf[self.cur..self.len]) {
-                Some(i) => {
-                    let line = &self.buf[self.cur..self.cur + i];
-                    self.cur += i + 1;
-                    if self.line.is_empty() {
-                        return Ok(Some(line));
-                    } else {
-                        self.line.extend_from_slice(line);
-                        return Ok(Some(&self.line));
-                    }
-                }
-                None => {
-                    self.line.extend_from_slice(&self.buf[self.cur..self.len]);
-                    self.cur = self.len;
-                }
-            }
-            if self.cur >= self.len {
-                self.len = self.reader.read(&mut self.buf[..])?;
-                self.cur = 0;
-                if self.len == 0 {
-                    if self.line.is_empty() {
-                        return Ok(None);
-                    } else {
-                        return Ok(Some(&self.line));
-                    }
-                }
+/// The CPU time which every thread of the process has spent so far in user
+/// and system mode, or `None` where it is not measured.
+fn cpu_time() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        let mut usage = MaybeUninit::<libc::rusage>::uninit();
+        // SAFETY: `getrusage` initializes `usage` when it succeeds.
+        let usage = unsafe {
+            if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+                return None;
             }
+            usage.assume_init()
+        };
+        let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        Some(time(usage.ru_utime) + time(usage.ru_stime))
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Prints the timings and, with `--phases` and `--mem`, the phase timings and
+/// allocations of the last run.
+fn print_summary<W: Write>(
+    w: &mut W,
+    strategy: &str,
+    times: &Times,
+    warmup: bool,
+    config: &Config,
+) -> io::Result<()> {
+    if config.bench_format == BenchFormat::Json {
+        return print_summary_json(w, strategy, times, warmup, config);
+    }
+    print_timings(w, times, warmup)?;
+    match times.throughput() {
+        Some((Some(mb), rows)) => writeln!(w, "Throughput: {mb:.1} MB/s {rows:.0} rows/s")?,
+        Some((None, rows)) => writeln!(w, "Throughput: {rows:.0} rows/s")?,
+        None => {}
+    }
+    if config.phases {
+        let phases = phases::stats(times.last());
+        writeln!(
+            w,
+            "Phases: header={:?} rows={:?} output={:?}",
+            phases.header, phases.rows, phases.output,
+        )?;
+    }
+    if let Some(stats) = mem::stats() {
+        writeln!(
+            w,
+            "Memory: peak={} bytes total={} bytes allocs={}",
+            stats.peak, stats.total, stats.allocs,
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints the timings like `print_summary`, as one JSON object on a line with
+/// times in milliseconds.
+fn print_summary_json<W: Write>(
+    w: &mut W,
+    strategy: &str,
+    times: &Times,
+    warmup: bool,
+    config: &Config,
+) -> io::Result<()> {
+    write!(w, "{{\"strategy\":\"{strategy}\",\"warmup\":{warmup},")?;
+    write_times_json(w, &times.wall)?;
+    if !times.cpu.is_empty() {
+        write!(w, ",\"cpu\":{{")?;
+        write_times_json(w, &times.cpu)?;
+        write!(w, "}}")?;
+    }
+    if let Some((mb, rows)) = times.throughput() {
+        if let Some(mb) = mb {
+            write!(w, ",\"mb_per_s\":{mb:.1}")?;
         }
+        write!(w, ",\"rows_per_s\":{rows:.0}")?;
+    }
+    if config.phases {
+        let phases = phases::stats(times.last());
+        write!(
+            w,
+            ",\"phases\":{{\"header\":{:.3},\"rows\":{:.3},\"output\":{:.3}}}",
+            ms(phases.header),
+            ms(phases.rows),
+            ms(phases.output),
+        )?;
     }
+    if let Some(stats) = mem::stats() {
+        write!(
+            w,
+            ",\"mem\":{{\"peak\":{},\"total\":{},\"allocs\":{}}}",
+            stats.peak, stats.total, stats.allocs,
+        )?;
+    }
+    writeln!(w, "}}")
 }
 
-#[inline]
-fn calc_custom_read<R: Read>(reader: R) -> io::Result<()> {
-    let mut reader = LineReader::new(reader);
+/// Writes the time of each run and their statistics as JSON object members.
+fn write_times_json<W: Write>(w: &mut W, times: &[Duration]) -> io::Result<()> {
+    write!(w, "\"runs\":[")?;
+    for (i, &time) in times.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(w, "{sep}{:.3}", ms(time))?;
+    }
+    let summary = Summary::new(times);
+    write!(
+        w,
+        "],\"min\":{:.3},\"median\":{:.3},\"mean\":{:.3},\"p95\":{:.3},\"max\":{:.3}",
+        ms(summary.min),
+        ms(summary.median),
+        ms(summary.mean),
+        ms(summary.p95),
+        ms(summary.max),
+    )
+}
 
-    let header = reader.next_line()?.unwrap();
-    let (idx, _header_len) = ColIndices::from_header(&header);
-    let useful_cols = idx.useful_cols();
+/// A duration in fractional milliseconds.
+fn ms(time: Duration) -> f64 {
+    time.as_secs_f64() * 1e3
+}
 
-    let mut products = hashbrown::HashMap::<Box<[u8]>, ProductData>::new();
-    let mut cols_empty: Vec<&'static [u8]> = Vec::with_capacity(useful_cols);
-    while let Some(line) = reader.next_line()? {
-        if line.len() == 0 {
-            continue;
-        }
-        let mut cols = cols_empty;
-        cols.extend(line.split(|&b| b == b',').take(useful_cols));
-        if cols[idx.source] == b"ToClnt" {
-            let prod = products.entry_ref(cols[idx.prod]).or_default();
-            prod.process_row(&cols, &idx);
+/// Runs a strategy `config.runs` times and returns the elapsed time of each
+/// run. When there are several runs, the first is a warmup and is excluded.
+fn time_runs<S: Sink>(
+    strategy: &str,
+    inputs: &[PathBuf],
+    config: &Config,
+    sink: &mut S,
+) -> Result<Times, Error> {
+    let mut times = Times {
+        wall: Vec::with_capacity(config.runs),
+        cpu: Vec::with_capacity(config.runs),
+        rows: None,
+        bytes: input_bytes(inputs),
+    };
+    let mut sink = CountRows { sink, rows: 0 };
+    for _ in 0..config.runs {
+        mem::reset();
+        phases::reset();
+        times.time(|| run_strategy(strategy, inputs, config, &mut sink))?;
+    }
+    times.rows = Some(sink.rows);
+    if times.wall.len() > 1 {
+        times.wall.remove(0);
+        if !times.cpu.is_empty() {
+            times.cpu.remove(0);
         }
-        cols_empty = cols.into_iter().take(0).map(|_| &[][..]).collect();
     }
-    print_products(products.iter().map(|(k, v)| (&**k, v)));
-    Ok(())
+    Ok(times)
 }
 
-#[derive(Default)]
-struct ProductData {
-    count: u32,
-    buys: u32,
-    sells: u32,
-    total_qty: u32,
+/// Prints the elapsed time of a single run or summarizes several, with the
+/// CPU time where it is measured.
+fn print_timings<W: Write>(w: &mut W, times: &Times, warmup: bool) -> io::Result<()> {
+    if !warmup {
+        return match times.cpu.first() {
+            Some(cpu) => writeln!(w, "Elapsed: wall={:?} cpu={cpu:?}", times.wall[0]),
+            None => writeln!(w, "Elapsed: {:?}", times.wall[0]),
+        };
+    }
+    writeln!(w, "Runs: {} (after 1 warmup)", times.wall.len())?;
+    let summary = Summary::new(&times.wall);
+    writeln!(w, "Elapsed: wall {summary}")?;
+    if !times.cpu.is_empty() {
+        writeln!(w, "Elapsed: cpu  {}", Summary::new(&times.cpu))?;
+    }
+    Ok(())
 }
 
-struct ColIndices {
-    source: usize,
-    bs: usize,
-    ordqty: usize,
-    wrkqty: usize,
-    excqty: usize,
-    prod: usize,
+/// Statistics of the elapsed times of several runs.
+struct Summary {
+    min: Duration,
+    median: Duration,
+    mean: Duration,
+    p95: Duration,
+    max: Duration,
 }
 
-impl ColIndices {
-    #[inline]
-    fn from_header(header: &[u8]) -> (ColIndices, usize) {
-        let mut source_idx = None;
-        let mut bs_idx = None;
-        let mut ordqty_idx = None;
-        let mut wrkqty_idx = None;
-        let mut excqty_idx = None;
-        let mut prod_idx = None;
-        let mut cols = 0;
-        for (i, col) in header.split(|&b| b == b',').enumerate() {
-            match col {
-                b"Source" => source_idx = Some(i),
-                b"B/S" => bs_idx = Some(i),
-                b"OrdQty" => ordqty_idx = Some(i),
-                b"WrkQty" => wrkqty_idx = Some(i),
-                b"ExcQty" => excqty_idx = Some(i),
-                b"Prod" => prod_idx = Some(i),
-                _ => {}
-            }
-            cols += 1;
+impl Summary {
+    fn new(times: &[Duration]) -> Self {
+        let mut sorted = times.to_vec();
+        sorted.sort_unstable();
+        let n = sorted.len();
+        Summary {
+            min: sorted[0],
+            median: median(&sorted),
+            mean: sorted.iter().sum::<Duration>() / n as u32,
+            // Nearest-rank percentile.
+            p95: sorted[(n * 95).div_ceil(100) - 1],
+            max: sorted[n - 1],
         }
-        let indices = ColIndices {
-            source: source_idx.unwrap(),
-            bs: bs_idx.unwrap(),
-            ordqty: ordqty_idx.unwrap(),
-            wrkqty: wrkqty_idx.unwrap(),
-            excqty: excqty_idx.unwrap(),
-            prod: prod_idx.unwrap(),
-        };
-        (indices, cols)
     }
+}
 
-    #[inline]
-    fn useful_cols(&self) -> usize {
-        (self.source.max(self.bs)).max(self.ordqty.max(self.wrkqty).max(self.excqty.max(self.prod)))
-            + 1
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min={:?} median={:?} mean={:?} p95={:?} max={:?}",
+            self.min, self.median, self.mean, self.p95, self.max,
+        )
     }
 }
 
-impl ProductData {
-    #[inline]
-    fn process_row(&mut self, cols: &[&[u8]], idx: &ColIndices) {
-        #[inline]
-        fn parse_u32(s: &[u8]) -> u32 {
-            // SAFETY: The grammar for u32::from_str_radix is all ASCII and it
-            // parses as bytes, rejecting any non-ASCII sequences, so it handles
-            // invalid UTF-8 safely.
-            let s = unsafe { str::from_utf8_unchecked(s) };
-            s.parse().unwrap()
+/// The median of sorted durations.
+fn median(sorted: &[Duration]) -> Duration {
+    let n = sorted.len();
+    if n.is_multiple_of(2) {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2
+    } else {
+        sorted[n / 2]
+    }
+}
+
+/// Times every strategy on the same inputs, checks that they agree like
+/// `verify`, and prints a table of the timings, fastest first, with the
+/// median CPU time, which exceeds the wall-clock time by the parallelism of a
+/// strategy. With `--bench-format json`, the timings of each strategy are
+/// instead printed as a line of JSON once it has run.
+fn bench_all(inputs: &[PathBuf], config: &Config) -> Result<(), Error> {
+    let json = config.bench_format == BenchFormat::Json;
+    let mut expected = Expected::default();
+    let mut results = Vec::with_capacity(STRATEGIES.len());
+    for &strategy in STRATEGIES {
+        let mut sink = Collect::default();
+        let mut times = match time_runs(strategy, inputs, config, &mut sink) {
+            Ok(times) => times,
+            Err(Error::Unsupported(reason)) if json => {
+                eprintln!("{strategy}: skipped ({reason})");
+                continue;
+            }
+            Err(Error::Unsupported(reason)) => {
+                println!("{strategy}: skipped ({reason})");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if json {
+            let warmup = config.runs > 1;
+            print_summary_json(&mut io::stdout().lock(), strategy, &times, warmup, config)?;
+        }
+        expected.check(strategy, sink.products, config)?;
+        times.wall.sort_unstable();
+        times.cpu.sort_unstable();
+        let cpu = (!times.cpu.is_empty()).then(|| median(&times.cpu));
+        // The counts are of the last run, since each run resets them.
+        let allocs = mem::stats().map(|stats| stats.allocs);
+        results.push((strategy, times.wall[0], median(&times.wall), cpu, allocs));
+    }
+    if json {
+        return Ok(());
+    }
+    results.sort_by_key(|&(_, _, median, _, _)| median);
+    print!(
+        "{:<18} {:>12} {:>12} {:>12}",
+        "Strategy", "Min", "Median", "CPU"
+    );
+    if config.mem {
+        print!(" {:>12}", "Allocs");
+    }
+    println!();
+    for (strategy, min, median, cpu, allocs) in results {
+        match cpu {
+            Some(cpu) => print!("{strategy:<18} {min:>12.2?} {median:>12.2?} {cpu:>12.2?}"),
+            None => print!("{strategy:<18} {min:>12.2?} {median:>12.2?} {:>12}", "-"),
+        }
+        if let Some(allocs) = allocs {
+            print!(" {allocs:>12}");
         }
+        println!();
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprint!(
+        "\
+Usage: cargo run --release <data>... <strategy> [options]
+
+The data path `-` reads from stdin, which only the streaming strategies (read,
+read-memchr, custom-read, block-read, pipeline, and read-offsets) support.
+Several data paths are aggregated together by the streaming strategies and
+read-memmap, and must have their columns in the same positions.
 
-        self.count += 1;
-        match cols[idx.bs] {
-            b"Buy" => self.buys += 1,
-            b"Sell" => self.sells += 1,
+Strategies:
+    fulltext
+    fulltext-reuse  fulltext, reading into a buffer kept across --runs
+    fulltext-parallel
+    memmap-ref
+    memmap-clone
+    memmap-seq      memmap-ref, with sequential and will-need access advice
+    memmap-memchr   read-memchr, directly over the memmap without copying lines
+    memmap-parallel
+    read
+    read-memmap
+    read-memchr
+    custom-read
+    block-read      custom-read, processing lines in place in each block read
+    simd
+    pipeline
+    read-offsets
+    bstr            memmap-clone, splitting with the bstr crate instead of
+                    memchr
+    verify          Run every strategy and check that their results agree
+    bench-all       Time every strategy, check that their results agree, and
+                    tabulate the timings, fastest first
+    inspect         Print the columns of the header, marking with * those
+                    which are aggregated, without reading any rows
+    filter          Print the lines of the rows which pass the filters, after
+                    the header, instead of aggregating them
+
+Options:
+    --delimiter <char>  Field separator (default: ','). Accepts '\\t' for tab.
+    --delimiter-auto    Detect the field separator from the header of the first
+                        input, as the most frequent of ',', tab, '|', and ';'.
+    --sort <order>      Output order: none (default), symbol, or count.
+    --format <format>   Output format: text (default), json, csv, or bin, which
+                        writes for each product its length as a little-endian
+                        u32 and its bytes, then count, buys, and sells as u32,
+                        total quantity as u64, and max quantity as u32, and
+                        ignores --fields.
+    --fields <names>    Print only these comma-separated metrics of each
+                        product, in order, from count, buys, sells, avg_qty,
+                        min_qty, max_qty, buy_qty, sell_qty, exc_total,
+                        stddev_qty, buy_pct, rate, vwap, qty_hist, first_line,
+                        and last_line. Selecting rate, vwap, qty_hist, or the
+                        lines enables what --stats, --with-price, --histogram,
+                        or --trace would record for them.
+    --precision <n>     Print float metrics, such as avg qty, with n decimal
+                        places, instead of 1 to 4 depending on the metric.
+    --rfc4180           Allow fields to be enclosed in double quotes to contain
+                        the delimiter, with \"\" as an escaped quote.
+    --comment <char>    Skip lines starting with this character. Lines which
+                        repeat the header, as in concatenated files, are always
+                        skipped.
+    --case-insensitive-header
+                        Match column names in the header ignoring ASCII case,
+                        such as ORDQTY or ordqty for OrdQty.
+    --no-header         The first line of each input is a row, not a header.
+                        Requires --columns.
+    --columns <spec>    The 0-based indices of the columns with --no-header, as
+                        name=index pairs separated by commas, such as
+                        source=0,bs=3,prod=7,ordqty=10,wrkqty=11,excqty=12.
+                        Names are source, bs, prod, ordqty, wrkqty, excqty,
+                        time, price, or the name of a --qty-col or --group-by
+                        column.
+    --schema <path>     Load column names or indices, the B/S tokens, and the
+                        Source to aggregate from a file of key = value lines,
+                        such as ordqty = \"Quantity\" or buy_token = \"B\". Keys
+                        are the names in --columns, qty, qty_index, buy_token,
+                        sell_token, source_filter, delimiter, and no_header.
+                        Indices require no_header = true. Later options
+                        override it.
+    --skip-rows <n>     Discard the first n lines of each input, such as
+                        metadata before the header (default: 0).
+    --runs <n>          Run the strategy n times and summarize the timings,
+                        discarding the first run as a warmup (default: 1).
+    --bench-format <format>
+                        Timing format: text (default) or json, a line with
+                        the time of each run in milliseconds and statistics.
+                        It is printed to stdout with --quiet or --output, and
+                        otherwise to stderr, apart from the products.
+    --quiet             Aggregate the products, but do not print them, to time
+                        parsing separately from formatting.
+    --count-only        Print only the number of rows which pass the filters,
+                        without aggregating them, to time reading and
+                        filtering alone. Only supported by the streaming
+                        strategies and read-memmap.
+    --follow            Keep reading the input as it is appended to, like
+                        tail -f, re-printing the products whenever the end is
+                        reached after new rows, until --limit rows. Only
+                        supported by read, for a single uncompressed file.
+    --follow-interval <ms>
+                        Poll for appended rows at this interval, implying
+                        --follow (default: 1000).
+    --follow-reset      Clear the products and counts each time they are
+                        printed with --follow, so that each print covers only
+                        the new rows.
+    --source <value>    Only aggregate rows with this Source (default: ToClnt),
+                        or all rows for `all`.
+    --prod <value>      Only aggregate rows with this Prod, or --group-by
+                        column.
+    --normalize-keys    Uppercase the ASCII letters of products, so that
+                        symbols differing only by case are aggregated
+                        together. Normalized keys are not in the input, so
+                        the strategies which borrow keys from it fall back to
+                        copying every key, as memmap-clone does, and the
+                        parallel strategies, simd, and memmap-memchr do not
+                        support it.
+    --exclude-blank-products
+                        Skip rows with an empty Prod, which are otherwise
+                        aggregated as <empty>.
+    --buy-token <tok>   The B/S value of buy rows (default: Buy).
+    --sell-token <tok>  The B/S value of sell rows (default: Sell).
+    --warm-cache        Read the inputs once before timing, so that every
+                        strategy starts with them in the page cache. Timings
+                        then compare parsing alone, while timings from a cold
+                        cache also compare how strategies wait on the disk.
+    --mem               Report the peak and total bytes allocated and the number
+                        of allocations by the last run, which bench-all also
+                        tabulates for each strategy.
+    --phases            Report the time the last run spent until the first
+                        header was parsed, aggregating rows, and printing
+                        products.
+    --threads <n>       Run the parallel strategies on n threads (default:
+                        one per logical core).
+    --progress          Report the lines and bytes processed to stderr every
+                        million lines. Not supported by the parallel
+                        strategies.
+    --after <time>      Only aggregate rows with a Time of at least HH:MM:SS.
+    --before <time>     Only aggregate rows with a Time earlier than HH:MM:SS.
+    --top <n>           Only print the n products with the most rows, in
+                        descending order, unless --sort is also given.
+    --top-by <metric>   Rank products for --top by count (default) or qty, the
+                        total row quantity.
+    --histogram         Also print the number of rows with quantities of each
+                        power of ten: 0, 1-9, 10-99, and so on.
+    --trace             Also print the lines of the first and last row of each
+                        product, within its input.
+    --safe-keys         Escape control characters and invalid UTF-8 in printed
+                        products, as \\n or \\xff.
+    --check-invariants  Report to stderr the number of aggregated rows without
+                        ExcQty <= WrkQty <= OrdQty and the lines of the first
+                        few.
+    --products-hint <n> Allocate maps for n products up front, so that they are
+                        not rehashed as they grow.
+    --limit <n>         Stop after n data rows, excluding the header and blank
+                        lines. Timings are not comparable to full runs.
+    --output <path>     Print products to this file instead of stdout. With
+                        --runs, it holds the output of the last run.
+    --split-output <dir>
+                        Print each product to <dir>/<prod>.txt instead, with
+                        / and control characters in <prod> replaced by _, and
+                        the counts to stderr.
+    --line-stats        Print the number of lines after the header and the
+                        shortest, longest, and mean length of those which are
+                        not blank, in bytes without line endings, instead of
+                        running the strategy.
+    --source-summary    Print the number of rows from each Source, most common
+                        first, with the counts to stderr, instead of running
+                        the strategy. Every Source is tallied, but the other
+                        filters apply.
+    --buf-size <bytes>  Buffer size for custom-read and block size for
+                        block-read, with an optional k, M, or G suffix
+                        (default: 32k).
+    --qty-col <name>    Take row quantities from this column, instead of the
+                        combination of OrdQty, WrkQty, and ExcQty by
+                        --qty-reduce.
+    --qty-reduce <reducer>
+                        Combine OrdQty, WrkQty, and ExcQty into the quantity
+                        of a row by max (default), sum, min, or first, the
+                        first which is nonzero.
+    --null-qty-policy <policy>
+                        Handle rows with an empty quantity by failing (error,
+                        the default), aggregating it as 0 (zero), or skipping
+                        the row (skip).
+    --max-qty <n>       Report to stderr each row with a quantity above n as
+                        suspicious, with its line, and skip it.
+    --max-qty-policy <policy>
+                        Handle rows above --max-qty by skipping them (skip, the
+                        default) or aggregating their quantity as n (clamp).
+    --group-by <name>   Aggregate rows grouped by this column instead of Prod,
+                        by header name or a name in --columns, such as time.
+                        With a suffix of ,bs, as in prod,bs, rows are grouped
+                        by it and B/S together, which only the strategies
+                        which copy keys (memmap-clone, read-memmap, and the
+                        streaming strategies) support.
+    --with-price        Report the volume-weighted average price of each
+                        product from the Price column.
+    --stats             Also print the standard deviation of row quantities,
+                        the percentage of Buy and Sell rows which are buys,
+                        and, when there is a Time column of HH:MM:SS or epoch
+                        seconds, the rows per second between the first and
+                        last.
+    --strict            Fail on rows with a different number of fields than
+                        the header, instead of counting and skipping rows too
+                        short to aggregate as malformed.
+    --validate-utf8     Fail on rows with a product or B/S which is not valid
+                        UTF-8, reporting the line and byte.
+"
+    );
+    process::exit(2);
+}
+
+fn parse_args() -> (Vec<PathBuf>, OsString, Config) {
+    let mut args = env::args_os().skip(1);
+    let mut positional = Vec::new();
+    let mut config = Config::default();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--delimiter") => {
+                let value = args.next().unwrap_or_else(|| usage());
+                config.delimiter = parse_delimiter(&value).unwrap_or_else(|| usage());
+            }
+            Some("--comment") => {
+                let value = next_value(&mut args);
+                config.comment = Some(parse_delimiter(&value).unwrap_or_else(|| usage()));
+            }
+            Some("--sort") => {
+                config.sort = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("none") => SortOrder::None,
+                    Some("symbol") => SortOrder::Symbol,
+                    Some("count") => SortOrder::Count,
+                    _ => usage(),
+                };
+            }
+            Some("--no-header") => config.no_header = true,
+            Some("--case-insensitive-header") => config.case_insensitive_header = true,
+            Some("--schema") => {
+                let path = PathBuf::from(args.next().unwrap_or_else(|| usage()));
+                if let Err(err) = load_schema(&path, &mut config) {
+                    eprintln!("error: {err}");
+                    process::exit(1);
+                }
+            }
+            Some("--skip-rows") => config.skip_rows = next_value(&mut args),
+            Some("--columns") => {
+                let value = args.next().unwrap_or_else(|| usage());
+                config.columns = parse_columns(&value).unwrap_or_else(|| usage());
+            }
+            Some("--delimiter-auto") => config.delimiter_auto = true,
+            Some("--normalize-keys") => config.normalize_keys = true,
+            Some("--rfc4180") => config.rfc4180 = true,
+            Some("--quiet") => config.quiet = true,
+            Some("--count-only") => config.count_only = true,
+            Some("--follow") => {
+                config.follow.get_or_insert(Duration::from_secs(1));
+            }
+            Some("--follow-interval") => {
+                config.follow = Some(Duration::from_millis(next_value(&mut args)));
+            }
+            Some("--follow-reset") => config.follow_reset = true,
+            Some("--mem") => config.mem = true,
+            Some("--warm-cache") => config.warm_cache = true,
+            Some("--phases") => config.phases = true,
+            Some("--progress") => config.progress = true,
+            Some("--with-price") => config.with_price = true,
+            Some("--strict") => config.strict = true,
+            Some("--validate-utf8") => config.validate_utf8 = true,
+            Some("--stats") => config.stats = true,
+            Some("--exclude-blank-products") => config.exclude_blank_products = true,
+            Some("--source") => {
+                config.source = match next_value::<String>(&mut args).as_str() {
+                    "all" => None,
+                    source => Some(source.as_bytes().into()),
+                };
+            }
+            Some("--prod") => {
+                config.prod = Some(next_value::<String>(&mut args).into_bytes().into());
+            }
+            Some("--buy-token") => {
+                config.buy_token = next_value::<String>(&mut args).into_bytes().into();
+            }
+            Some("--sell-token") => {
+                config.sell_token = next_value::<String>(&mut args).into_bytes().into();
+            }
+            Some("--format") => {
+                config.format = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("text") => Format::Text,
+                    Some("json") => Format::Json,
+                    Some("csv") => Format::Csv,
+                    Some("bin") => Format::Bin,
+                    _ => usage(),
+                };
+            }
+            Some("--precision") => config.precision = Some(next_value(&mut args)),
+            Some("--fields") => {
+                let value = args.next().unwrap_or_else(|| usage());
+                config.fields = parse_fields(&value);
+            }
+            Some("--bench-format") => {
+                config.bench_format = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("text") => BenchFormat::Text,
+                    Some("json") => BenchFormat::Json,
+                    _ => usage(),
+                };
+            }
+            Some("--line-stats") => config.line_stats = true,
+            Some("--source-summary") => config.source_summary = true,
+            Some("--split-output") => {
+                config.split_output = Some(args.next().unwrap_or_else(|| usage()).into());
+            }
+            Some("--output") => {
+                config.output = Some(args.next().unwrap_or_else(|| usage()).into());
+            }
+            Some("--after") => config.after = Some(next_time(&mut args)),
+            Some("--before") => config.before = Some(next_time(&mut args)),
+            Some("--top") => config.top = Some(next_value(&mut args)),
+            Some("--top-by") => {
+                config.top_by = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("count") => TopBy::Count,
+                    Some("qty") => TopBy::Qty,
+                    _ => usage(),
+                };
+            }
+            Some("--max-qty") => config.max_qty = Some(next_value(&mut args)),
+            Some("--max-qty-policy") => {
+                config.max_qty_policy = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("skip") => MaxQtyPolicy::Skip,
+                    Some("clamp") => MaxQtyPolicy::Clamp,
+                    _ => usage(),
+                };
+            }
+            Some("--null-qty-policy") => {
+                config.null_qty = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("error") => NullQty::Error,
+                    Some("zero") => NullQty::Zero,
+                    Some("skip") => NullQty::Skip,
+                    _ => usage(),
+                };
+            }
+            Some("--qty-reduce") => {
+                config.qty_reduce = match args.next().as_ref().and_then(|v| v.to_str()) {
+                    Some("max") => QtyReduce::Max,
+                    Some("sum") => QtyReduce::Sum,
+                    Some("first") => QtyReduce::First,
+                    Some("min") => QtyReduce::Min,
+                    _ => usage(),
+                };
+            }
+            Some("--histogram") => config.histogram = true,
+            Some("--trace") => config.trace = true,
+            Some("--safe-keys") => config.safe_keys = true,
+            Some("--check-invariants") => config.check_invariants = true,
+            Some("--products-hint") => config.products_hint = next_value(&mut args),
+            Some("--limit") => config.limit = next_value(&mut args),
+            Some("--buf-size") => {
+                let value = args.next().unwrap_or_else(|| usage());
+                config.buf_size = parse_size(&value).unwrap_or_else(|| usage());
+            }
+            Some("--group-by") => config.set_group_by(&next_value::<String>(&mut args)),
+            Some("--qty-col") => {
+                // Leaked, so that it can name the column in errors, like the
+                // built-in columns.
+                config.qty_col = Some(next_value::<String>(&mut args).leak());
+            }
+            Some("--threads") => {
+                let threads = next_value(&mut args);
+                if threads == 0 {
+                    usage();
+                }
+                config.threads = Some(threads);
+            }
+            Some("--runs") => {
+                config.runs = next_value(&mut args);
+                if config.runs == 0 {
+                    usage();
+                }
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 || config.no_header == config.columns.is_empty() {
+        usage();
+    }
+    for field in &config.fields {
+        match field {
+            OutputField::Rate => config.stats = true,
+            OutputField::Vwap => config.with_price = true,
+            OutputField::QtyHist => config.histogram = true,
+            OutputField::FirstLine | OutputField::LastLine => config.trace = true,
             _ => {}
         }
-        let ordqty = parse_u32(cols[idx.ordqty]);
-        let wrkqty = parse_u32(cols[idx.wrkqty]);
-        let excqty = parse_u32(cols[idx.excqty]);
-        self.total_qty += ordqty.max(wrkqty.max(excqty));
     }
+    let strategy = positional.pop().unwrap();
+    let inputs = positional.into_iter().map(PathBuf::from).collect();
+    (inputs, strategy, config)
+}
 
-    #[inline]
-    fn fmt<W: Write>(&self, w: &mut W, prod: &[u8]) -> io::Result<()> {
-        w.write_all(prod)?;
-        writeln!(
-            w,
-            " cnt={:4} buy={:4} sell={:4} avg qty={:6.2}",
-            self.count,
-            self.buys,
-            self.sells,
-            self.total_qty as f64 / self.count as f64,
-        )
+/// Parses the value of an option.
+fn next_value<T: FromStr>(args: &mut impl Iterator<Item = OsString>) -> T {
+    args.next()
+        .as_ref()
+        .and_then(|value| value.to_str()?.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+/// Parses the next argument as an `HH:MM:SS` time.
+fn next_time(args: &mut impl Iterator<Item = OsString>) -> u32 {
+    args.next()
+        .and_then(|value| parse_time(value.to_str()?.as_bytes()))
+        .unwrap_or_else(|| usage())
+}
+
+/// Parses a nonzero byte size with an optional binary suffix of `k`, `M`, or
+/// `G`, such as `64k`.
+fn parse_size(value: &OsString) -> Option<usize> {
+    let value = value.to_str()?;
+    let (digits, scale) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 1 << 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 1 << 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let size = digits.parse::<usize>().ok()?.checked_mul(scale)?;
+    (size != 0).then_some(size)
+}
+
+/// Parses `--columns` as comma-separated `name=index` pairs, where the names
+/// of the built-in columns are lowercase and others are taken as the header
+/// name of a `--qty-col` or `--group-by` column.
+fn parse_columns(value: &OsString) -> Option<Vec<(Box<str>, usize)>> {
+    let mut columns = Vec::new();
+    for pair in value.to_str()?.split(',') {
+        let (name, index) = pair.split_once('=')?;
+        let name = builtin_column(name).unwrap_or(name);
+        columns.push((name.into(), index.parse().ok()?));
     }
+    Some(columns)
+}
+
+/// Parses `--fields` as comma-separated field names, exiting with the valid
+/// names for one which is unknown.
+fn parse_fields(value: &OsString) -> Vec<OutputField> {
+    let Some(value) = value.to_str() else {
+        usage();
+    };
+    value
+        .split(',')
+        .map(|name| {
+            OutputField::from_name(name).unwrap_or_else(|| {
+                let names: Vec<_> = OutputField::ALL.iter().map(|f| f.name()).collect();
+                eprintln!(
+                    "error: unknown field `{name}`; valid fields are {}",
+                    names.join(", "),
+                );
+                process::exit(2);
+            })
+        })
+        .collect()
 }
 
-#[inline]
-fn print_products<'a>(iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>) {
-    let mut stdout = io::stdout().lock();
-    for (prod, data) in iter {
-        data.fmt(&mut stdout, prod).unwrap();
+/// Parses a delimiter given as a single ASCII character or as one of the
+/// escapes `\t` or `\0`.
+fn parse_delimiter(value: &OsString) -> Option<u8> {
+    match value.to_str()?.as_bytes() {
+        b"\\t" => Some(b'\t'),
+        b"\\0" => Some(b'\0'),
+        &[b] if b.is_ascii() => Some(b),
+        _ => None,
     }
 }
@@ -0,0 +1,117 @@
+//! A global allocator, which counts allocations and allocated bytes for
+//! `--mem`. Counting is off until [`enable`] is called, so that the other
+//! runs are not slowed by contended atomics on every allocation.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Wraps the system allocator and counts the allocations it makes and the
+/// bytes they hold.
+struct CountingAlloc;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The bytes live, which is signed, as allocations made before counting was
+/// enabled are not counted, but their frees are.
+static CURRENT: AtomicIsize = AtomicIsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocation counts since the last [`reset`].
+pub struct MemStats {
+    /// The most bytes live at once.
+    pub peak: usize,
+    /// The sum of the sizes of all allocations.
+    pub total: usize,
+    /// The number of allocations, including reallocations.
+    pub allocs: usize,
+}
+
+/// Starts counting allocations, for `--mem`.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Restarts counting peak and total allocations, when counting is enabled.
+pub fn reset() {
+    if !enabled() {
+        return;
+    }
+    let current = CURRENT.load(Ordering::Relaxed).max(0) as usize;
+    PEAK.store(current, Ordering::Relaxed);
+    TOTAL.store(0, Ordering::Relaxed);
+    ALLOCS.store(0, Ordering::Relaxed);
+}
+
+/// Returns the allocation counts since the last [`reset`], or `None` when
+/// counting is not enabled.
+pub fn stats() -> Option<MemStats> {
+    enabled().then(|| MemStats {
+        peak: PEAK.load(Ordering::Relaxed),
+        total: TOTAL.load(Ordering::Relaxed),
+        allocs: ALLOCS.load(Ordering::Relaxed),
+    })
+}
+
+#[inline]
+fn record_alloc(size: usize) {
+    if !enabled() {
+        return;
+    }
+    ALLOCS.fetch_add(1, Ordering::Relaxed);
+    let current = CURRENT.fetch_add(size as isize, Ordering::Relaxed) + size as isize;
+    PEAK.fetch_max(current.max(0) as usize, Ordering::Relaxed);
+    TOTAL.fetch_add(size, Ordering::Relaxed);
+}
+
+#[inline]
+fn record_dealloc(size: usize) {
+    if !enabled() {
+        return;
+    }
+    CURRENT.fetch_sub(size as isize, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        record_dealloc(layout.size());
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record_dealloc(layout.size());
+            record_alloc(new_size);
+        }
+        new_ptr
+    }
+}
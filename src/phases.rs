@@ -0,0 +1,63 @@
+//! Timing of the phases of a run, for `--phases`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static STATE: Mutex<State> = Mutex::new(State {
+    start: None,
+    header: None,
+    output: Duration::ZERO,
+});
+
+struct State {
+    start: Option<Instant>,
+    header: Option<Duration>,
+    output: Duration,
+}
+
+/// The time spent in each phase of a run.
+pub struct PhaseTimes {
+    /// From the start of the run until the first header was parsed, which
+    /// includes opening the input and reading up to the first row.
+    pub header: Duration,
+    /// Aggregating the rows.
+    pub rows: Duration,
+    /// Formatting and printing the products.
+    pub output: Duration,
+}
+
+/// Starts timing a run.
+pub fn reset() {
+    *STATE.lock().unwrap() = State {
+        start: Some(Instant::now()),
+        header: None,
+        output: Duration::ZERO,
+    };
+}
+
+/// Records that the header of an input has been parsed. Only the first
+/// header of a run is recorded, so that the header phase lasts until the
+/// first row.
+pub fn header_parsed() {
+    let mut state = STATE.lock().unwrap();
+    if state.header.is_none() {
+        state.header = state.start.map(|start| start.elapsed());
+    }
+}
+
+/// Records time spent printing the products.
+pub fn add_output(elapsed: Duration) {
+    STATE.lock().unwrap().output += elapsed;
+}
+
+/// Returns the time spent in each phase of the run since the last [`reset`],
+/// which took `total` overall.
+pub fn stats(total: Duration) -> PhaseTimes {
+    let state = STATE.lock().unwrap();
+    let header = state.header.unwrap_or_default();
+    PhaseTimes {
+        header,
+        rows: total.saturating_sub(header).saturating_sub(state.output),
+        output: state.output,
+    }
+}
@@ -0,0 +1,111 @@
+//! Compares what every strategy prints for a checked-in input with golden
+//! files in `tests/golden`, so that changes to the output formats are caught.
+//! Run with `UPDATE_EXPECT=1` to rewrite the golden files from the reference
+//! strategy after an intended change.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cliff_stock_bench::{run_strategy, Config, Error, Format, Print, SortOrder, STRATEGIES};
+
+/// The strategy which writes the golden files.
+const REFERENCE: &str = "fulltext";
+
+/// The directory of the input and golden files.
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// Prints `orders.csv` with a strategy, returning the output, or `None` when
+/// the strategy does not support the options.
+fn print(strategy: &str, name: &str, config: &Config) -> Option<String> {
+    let output = env::temp_dir().join(format!(
+        "cliff-stock-bench-{}-golden-{name}-{strategy}.out",
+        std::process::id(),
+    ));
+    let config = Config {
+        // Hash map order differs between strategies.
+        sort: SortOrder::Symbol,
+        output: Some(output.clone()),
+        ..config.clone()
+    };
+    let input = golden_dir().join("orders.csv");
+    match run_strategy(strategy, &[input], &config, &mut Print) {
+        Ok(()) => {}
+        Err(Error::Unsupported(_)) => return None,
+        Err(err) => panic!("{strategy} failed on {name}: {err}"),
+    }
+    let printed = fs::read_to_string(&output).unwrap();
+    fs::remove_file(&output).unwrap();
+    Some(printed)
+}
+
+/// Asserts that every strategy prints `<name>.expected` byte for byte, or
+/// with `UPDATE_EXPECT` set, first rewrites it with the output of the
+/// reference strategy.
+fn check(name: &str, config: &Config) {
+    let path = golden_dir().join(format!("{name}.expected"));
+    if env::var_os("UPDATE_EXPECT").is_some() {
+        fs::write(&path, print(REFERENCE, name, config).unwrap()).unwrap();
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("{}: {err}; run with UPDATE_EXPECT=1", path.display()));
+    for &strategy in STRATEGIES {
+        if let Some(actual) = print(strategy, name, config) {
+            assert!(
+                actual == expected,
+                "{strategy} differs from {}:\n{actual}",
+                path.display(),
+            );
+        }
+    }
+}
+
+/// Every metric, which the formats print by default only with these options.
+fn all_metrics(format: Format) -> Config {
+    Config {
+        format,
+        stats: true,
+        with_price: true,
+        histogram: true,
+        trace: true,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn text() {
+    check("text", &Config::default());
+    check("text-all", &all_metrics(Format::Text));
+    let config = Config {
+        precision: Some(4),
+        ..all_metrics(Format::Text)
+    };
+    check("text-precision", &config);
+}
+
+#[test]
+fn json() {
+    let config = Config {
+        format: Format::Json,
+        ..Config::default()
+    };
+    check("json", &config);
+    check("json-all", &all_metrics(Format::Json));
+    let config = Config {
+        precision: Some(0),
+        ..all_metrics(Format::Json)
+    };
+    check("json-precision", &config);
+}
+
+#[test]
+fn csv() {
+    let config = Config {
+        format: Format::Csv,
+        ..Config::default()
+    };
+    check("csv", &config);
+    check("csv-all", &all_metrics(Format::Csv));
+}
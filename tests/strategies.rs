@@ -0,0 +1,1254 @@
+//! Checks that every strategy aggregates the same results from small inputs
+//! covering edge cases of the format.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use cliff_stock_bench::{
+    aggregate, filter, line_stats, load_schema, qty_bucket, read_bin_product, run_strategy,
+    source_summary, Collect, Config, Error, FieldError, Format, HeaderError, MaxQtyPolicy, NullQty,
+    OutputField, Print, ProductData, QtyReduce, RowCounts, RowReader, SchemaError, Sink, SortOrder,
+    QTY_BUCKETS, STRATEGIES,
+};
+
+const HEADER: &str = "RecordNo,Date/Time,Exch,SrsKey,Source,Status,OrderNo,ExchOrderId,Action,B/S,OrdQty,WrkQty,ExcQty,Prod,Expiry,O/C,Time\n";
+
+/// Rows from each of the sources, of which only `ToClnt` is aggregated by
+/// default.
+const SOURCES: &str = "\
+0,2018-08-29T00:00:24,CME-C,00A0,FrmClnt,OK,0,80D8U,Change,Buy,292,292,292,CAX,JUN19,Open,09:00:00
+1,2018-08-29T00:00:24,CME-C,00A0,ToHst,OK,0,80D8U,Change,Buy,292,292,292,CAX,JUN19,Open,09:00:07
+2,2018-08-29T00:00:24,CME-C,00A0,ToClnt,OK,0,80D8U,Change,Buy,292,292,292,CAX,JUN19,Open,09:00:11
+3,2018-08-29T00:00:25,CME-C,00A1,ToClnt,OK,1,80D8V,Add,Sell,10,4,6,CAX,JUN19,Open,09:00:12
+4,2018-08-29T00:00:25,CME-C,00A2,FromClnt,OK,2,80D8W,Add,Sell,50,50,0,ZBX,JUN19,Open,09:00:13
+5,2018-08-29T00:00:26,CME-C,00A2,ToClnt,OK,2,80D8W,Add,Sell,50,50,0,ZBX,JUN19,Open,09:00:14
+6,2018-08-29T00:00:27,CME-C,00A3,ToClnt,OK,3,80D8X,Fill,Buy,7,0,7,ZBX,JUN19,Close,09:00:15
+";
+
+/// The strategy which the rest are compared to, as it most directly follows
+/// the reference implementation.
+const REFERENCE: &str = "fulltext";
+
+/// Writes an input to a temporary file, so that strategies which map or open
+/// files can read it.
+fn fixture(name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "cliff-stock-bench-{}-{name}.csv",
+        std::process::id(),
+    ));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Aggregates an input with a strategy, sorted by product.
+fn run(strategy: &str, path: &Path, config: &Config) -> Result<Vec<(Vec<u8>, ProductData)>, Error> {
+    let mut sink = Collect::default();
+    run_strategy(strategy, &[path.to_owned()], config, &mut sink)?;
+    sink.products
+        .sort_by(|(prod1, _), (prod2, _)| prod1.cmp(prod2));
+    Ok(sink.products)
+}
+
+/// Runs every strategy on an input and asserts that they agree, returning the
+/// results of the reference strategy.
+fn run_all(name: &str, contents: &str, config: &Config) -> Vec<(Vec<u8>, ProductData)> {
+    let path = fixture(name, contents);
+    let expected = run(REFERENCE, &path, config).unwrap();
+    for &strategy in STRATEGIES {
+        match run(strategy, &path, config) {
+            Ok(actual) => assert!(
+                agree(&actual, &expected),
+                "{strategy} disagrees with {REFERENCE} on {name}:\n{actual:#?}\n{expected:#?}",
+            ),
+            Err(Error::Unsupported(_)) => {}
+            Err(err) => panic!("{strategy} failed on {name}: {err}"),
+        }
+    }
+    fs::remove_file(&path).unwrap();
+    expected
+}
+
+/// Whether two sorted results hold the same products with aggregates which
+/// agree up to rounding.
+fn agree(actual: &[(Vec<u8>, ProductData)], expected: &[(Vec<u8>, ProductData)]) -> bool {
+    actual.len() == expected.len()
+        && actual
+            .iter()
+            .zip(expected)
+            .all(|((prod1, data1), (prod2, data2))| prod1 == prod2 && data1.approx_eq(data2))
+}
+
+/// Runs every strategy on an input and returns the error of each.
+fn errors(name: &str, contents: impl AsRef<[u8]>, config: &Config) -> Vec<(&'static str, Error)> {
+    let path = fixture(name, contents);
+    let errors = STRATEGIES
+        .iter()
+        .filter_map(|&strategy| match run(strategy, &path, config) {
+            Ok(_) => panic!("{strategy} succeeded on {name}"),
+            Err(Error::Unsupported(_)) => None,
+            Err(err) => Some((strategy, err)),
+        })
+        .collect();
+    fs::remove_file(&path).unwrap();
+    errors
+}
+
+/// The counts of a run, which `Collect` does not keep.
+#[derive(Default)]
+struct Counted {
+    processed: usize,
+    products: usize,
+    violations: usize,
+    first_violations: Vec<usize>,
+}
+
+impl Sink for Counted {
+    fn finish<'a>(
+        &mut self,
+        iter: impl Iterator<Item = (&'a [u8], &'a ProductData)>,
+        counts: &RowCounts,
+        _config: &Config,
+    ) -> io::Result<()> {
+        *self = Counted {
+            processed: counts.processed,
+            products: iter.count(),
+            violations: counts.violations,
+            first_violations: counts.first_violations.clone(),
+        };
+        Ok(())
+    }
+}
+
+/// Runs a strategy on an input and returns its counts.
+fn count(strategy: &str, path: &Path, config: &Config) -> Result<Counted, Error> {
+    let mut sink = Counted::default();
+    run_strategy(strategy, &[path.to_owned()], config, &mut sink)?;
+    Ok(sink)
+}
+
+fn products(results: &[(Vec<u8>, ProductData)]) -> Vec<&[u8]> {
+    results.iter().map(|(prod, _)| &prod[..]).collect()
+}
+
+#[test]
+fn sources() {
+    let results = run_all("sources", &format!("{HEADER}{SOURCES}"), &Config::default());
+    assert_eq!(products(&results), [&b"CAX"[..], b"ZBX"]);
+    let (cax, zbx) = (&results[0].1, &results[1].1);
+    assert_eq!((cax.count, cax.buys, cax.sells), (2, 1, 1));
+    assert_eq!((cax.total_qty, cax.buy_qty, cax.sell_qty), (302, 292, 10));
+    assert_eq!((zbx.count, zbx.buys, zbx.sells), (2, 1, 1));
+    assert_eq!((zbx.min_qty(), zbx.max_qty), (7, 50));
+}
+
+#[test]
+fn all_sources() {
+    let config = Config {
+        source: None,
+        ..Config::default()
+    };
+    let results = run_all("all-sources", &format!("{HEADER}{SOURCES}"), &config);
+    assert_eq!((results[0].1.count, results[1].1.count), (4, 3));
+}
+
+#[test]
+fn blank_lines() {
+    let contents = format!("{HEADER}\n{}\n\n", SOURCES.replace('\n', "\n\n"));
+    let expected = run_all(
+        "sources-only",
+        &format!("{HEADER}{SOURCES}"),
+        &Config::default(),
+    );
+    assert_eq!(
+        run_all("blank-lines", &contents, &Config::default()),
+        expected
+    );
+}
+
+#[test]
+fn missing_final_newline() {
+    let contents = format!("{HEADER}{}", SOURCES.trim_end());
+    let results = run_all("no-newline", &contents, &Config::default());
+    assert_eq!(results[1].1.count, 2);
+    assert_eq!(
+        run_all("crlf", &contents.replace('\n', "\r\n"), &Config::default()),
+        results
+    );
+}
+
+#[test]
+fn final_line_across_reads() {
+    let contents = format!("{HEADER}{}", SOURCES.trim_end());
+    let expected = aggregate(contents.as_bytes(), &Config::default()).unwrap();
+    let last_line = contents.rfind('\n').unwrap() + 1;
+    let path = fixture("across-reads", &contents);
+    let expected_blocks = run(REFERENCE, &path, &Config::default()).unwrap();
+    // Every size of at most the final line splits it across reads, with each
+    // possible part of it being in the last read.
+    for buf_size in 1..=contents.len() - last_line + 1 {
+        let config = Config {
+            buf_size,
+            source: None,
+            ..Config::default()
+        };
+        let mut reader = RowReader::new(contents.as_bytes(), &config).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = reader.next_row().unwrap() {
+            rows.push((row.line_num(), row.qty().unwrap()));
+        }
+        assert_eq!(rows.len(), 7, "with buf_size={buf_size}");
+        assert_eq!(rows[6], (8, 7), "with buf_size={buf_size}");
+
+        let config = Config {
+            buf_size,
+            ..Config::default()
+        };
+        let actual = aggregate(contents.as_bytes(), &config).unwrap();
+        assert_eq!(actual, expected, "with buf_size={buf_size}");
+        let actual = run("block-read", &path, &config).unwrap();
+        assert!(
+            actual == expected_blocks,
+            "block-read with buf_size={buf_size}"
+        );
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// A reader which is interrupted before every read and returns at most a few
+/// bytes at a time, like a pipe read while signals arrive.
+struct Interrupted<'a> {
+    data: &'a [u8],
+    interrupt: bool,
+}
+
+impl io::Read for Interrupted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        let n = buf.len().min(self.data.len()).min(3);
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn interrupted_reads() {
+    let contents = format!("{HEADER}{SOURCES}");
+    let expected = aggregate(contents.as_bytes(), &Config::default()).unwrap();
+    let reader = Interrupted {
+        data: contents.as_bytes(),
+        interrupt: false,
+    };
+    let actual = aggregate(reader, &Config::default()).unwrap();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn concatenated() {
+    let expected = run_all("single", &format!("{HEADER}{SOURCES}"), &Config::default());
+    let contents = format!("{HEADER}{SOURCES}# second session\n{HEADER}{SOURCES}");
+    let config = Config {
+        comment: Some(b'#'),
+        ..Config::default()
+    };
+    let results = run_all("concatenated", &contents, &config);
+    assert_eq!(products(&results), products(&expected));
+    for ((_, data), (_, single)) in results.iter().zip(&expected) {
+        assert_eq!(data.count, 2 * single.count);
+    }
+}
+
+#[test]
+fn count_only() {
+    let path = fixture("count-only", format!("{HEADER}{SOURCES}"));
+    let config = Config {
+        count_only: true,
+        ..Config::default()
+    };
+    let mut supported = 0;
+    for &strategy in STRATEGIES {
+        match count(strategy, &path, &config) {
+            Ok(counted) => {
+                assert_eq!((counted.processed, counted.products), (4, 0), "{strategy}");
+                supported += 1;
+            }
+            Err(Error::Unsupported(_)) => {}
+            Err(err) => panic!("{strategy} failed: {err}"),
+        }
+    }
+    assert_eq!(supported, 7);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn progress_unsupported() {
+    let config = Config {
+        progress: true,
+        ..Config::default()
+    };
+    // Every other strategy reads the header and fails on it, but the parallel
+    // strategies reject `--progress` up front.
+    let failed: Vec<_> = errors("progress", "a,b,c\n1,2,3\n", &config)
+        .into_iter()
+        .map(|(strategy, err)| {
+            assert!(matches!(err, Error::Header(_)), "{strategy}: {err}");
+            strategy
+        })
+        .collect();
+    let unsupported: Vec<_> = STRATEGIES
+        .iter()
+        .filter(|strategy| !failed.contains(strategy))
+        .collect();
+    assert_eq!(unsupported, [&"fulltext-parallel", &"memmap-parallel"]);
+}
+
+#[test]
+fn check_invariants() {
+    // The CAX sell and the ZBX fill have ExcQty > WrkQty, as do the replaced
+    // CAX buys. Rows from other sources are not checked.
+    let contents = format!(
+        "{HEADER}{SOURCES}{}",
+        SOURCES.replace(",292,292,292,", ",1,2,3,")
+    );
+    let path = fixture("invariants", &contents);
+    for qty_col in [None, Some("ExcQty")] {
+        let config = Config {
+            check_invariants: true,
+            qty_col,
+            ..Config::default()
+        };
+        for &strategy in STRATEGIES {
+            let counted = count(strategy, &path, &config).unwrap();
+            assert_eq!(counted.violations, 5, "{strategy}");
+            assert_eq!(counted.first_violations, [5, 8, 11, 12, 15], "{strategy}");
+        }
+    }
+    let counted = count("read", &path, &Config::default()).unwrap();
+    assert_eq!(counted.violations, 0);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn qty_reduce() {
+    let contents = format!("{HEADER}{SOURCES}");
+    for (reduce, cax, zbx) in [
+        (QtyReduce::Max, 302, 57),
+        (QtyReduce::Sum, 896, 114),
+        (QtyReduce::First, 302, 57),
+        (QtyReduce::Min, 296, 0),
+    ] {
+        let config = Config {
+            qty_reduce: reduce,
+            ..Config::default()
+        };
+        let results = run_all("qty-reduce", &contents, &config);
+        assert_eq!((results[0].1.total_qty, results[1].1.total_qty), (cax, zbx));
+    }
+}
+
+#[test]
+fn max_qty() {
+    let contents = format!("{HEADER}{SOURCES}");
+    // Only the CAX buy of 292 is above the bound.
+    for (policy, cax) in [
+        (MaxQtyPolicy::Skip, (1, 10)),
+        (MaxQtyPolicy::Clamp, (2, 110)),
+    ] {
+        let config = Config {
+            max_qty: Some(100),
+            max_qty_policy: policy,
+            ..Config::default()
+        };
+        let results = run_all("max-qty", &contents, &config);
+        let (cax_data, zbx_data) = (&results[0].1, &results[1].1);
+        assert_eq!((cax_data.count, cax_data.total_qty), cax);
+        assert_eq!((zbx_data.count, zbx_data.total_qty), (2, 57));
+    }
+}
+
+#[test]
+fn histogram() {
+    let config = Config {
+        histogram: true,
+        ..Config::default()
+    };
+    let results = run_all("histogram", &format!("{HEADER}{SOURCES}"), &config);
+    let (cax, zbx) = (&results[0].1, &results[1].1);
+    assert_eq!(cax.qty_hist[..4], [0, 0, 1, 1]);
+    assert_eq!(zbx.qty_hist[..4], [0, 1, 1, 0]);
+    let buckets = [0, 1, 9, 10, 99, 100, 4_294_967_295].map(qty_bucket);
+    assert_eq!(buckets, [0, 1, 1, 2, 2, 3, QTY_BUCKETS - 1]);
+}
+
+#[test]
+fn null_qty() {
+    // Blanks the ExcQty of the CAX sell.
+    let contents = format!("{HEADER}{}", SOURCES.replace(",10,4,6,", ",10,4,,"));
+    for (strategy, err) in errors("null-qty-error", &contents, &Config::default()) {
+        let empty = match &err {
+            Error::Parse {
+                line: 5,
+                err: FieldError::Invalid { col, value },
+            } => *col == "ExcQty" && value.is_empty(),
+            _ => false,
+        };
+        assert!(empty, "{strategy}: {err}");
+    }
+    let config = Config {
+        null_qty: NullQty::Zero,
+        ..Config::default()
+    };
+    let cax = &run_all("null-qty-zero", &contents, &config)[0].1;
+    assert_eq!((cax.count, cax.total_qty, cax.exc_total), (2, 302, 292));
+    let config = Config {
+        null_qty: NullQty::Skip,
+        ..Config::default()
+    };
+    let cax = &run_all("null-qty-skip", &contents, &config)[0].1;
+    assert_eq!((cax.count, cax.total_qty, cax.exc_total), (1, 292, 292));
+}
+
+#[test]
+fn empty_products() {
+    let contents = format!("{HEADER}{}", SOURCES.replace(",ZBX,", ",,"));
+    let results = run_all("empty-products", &contents, &Config::default());
+    assert_eq!(products(&results), [&b"<empty>"[..], b"CAX"]);
+    let config = Config {
+        exclude_blank_products: true,
+        ..Config::default()
+    };
+    assert_eq!(
+        products(&run_all("exclude-empty", &contents, &config)),
+        [b"CAX"]
+    );
+}
+
+#[test]
+fn leading_empty_field() {
+    // Every row starts with a delimiter, and a row of just a delimiter is too
+    // short to aggregate.
+    let rows: String = SOURCES
+        .lines()
+        .map(|row| format!(",{}\n", row.split_once(',').unwrap().1))
+        .collect();
+    let results = run_all(
+        "leading-empty",
+        &format!("{HEADER}{rows},\n"),
+        &Config::default(),
+    );
+    let expected = run_all("leading", &format!("{HEADER}{SOURCES}"), &Config::default());
+    assert_eq!(results, expected);
+
+    // The first column is tracked, so its empty field is aggregated.
+    let header = "Prod,Source,B/S,OrdQty,WrkQty,ExcQty\n";
+    let results = run_all(
+        "leading-prod",
+        &format!("{header},ToClnt,Buy,1,2,3\nCAX,ToClnt,Sell,4,5,6\n"),
+        &Config::default(),
+    );
+    assert_eq!(products(&results), [&b"<empty>"[..], b"CAX"]);
+    assert_eq!(results[0].1.total_qty, 3);
+}
+
+#[test]
+fn delimiter_auto() {
+    let config = Config {
+        delimiter_auto: true,
+        ..Config::default()
+    };
+    let expected = run_all("auto-comma", &format!("{HEADER}{SOURCES}"), &config);
+    for delim in ["\t", "|", ";"] {
+        let contents = format!("{HEADER}{SOURCES}").replace(',', delim);
+        assert_eq!(run_all("auto", &contents, &config), expected, "{delim:?}");
+    }
+
+    // A tie, including of none, is an error.
+    for (header, tied) in [("a;b|c\n", &b"|;"[..]), ("abc\n", b",\t|;")] {
+        for (strategy, err) in errors("auto-tie", header, &config) {
+            assert!(
+                matches!(
+                    &err,
+                    Error::Header(HeaderError::AmbiguousDelimiter(actual)) if actual == tied
+                ),
+                "{strategy}: {err}",
+            );
+        }
+    }
+}
+
+#[test]
+fn trailing_delimiter() {
+    let contents = format!("{HEADER}{}", SOURCES.replace('\n', ",\n"));
+    let results = run_all("trailing", &contents, &Config::default());
+    assert_eq!(products(&results), [&b"CAX"[..], b"ZBX"]);
+    let config = Config {
+        strict: true,
+        ..Config::default()
+    };
+    for (strategy, err) in errors("trailing-strict", &contents, &config) {
+        assert!(
+            matches!(
+                err,
+                Error::Parse {
+                    line: 2,
+                    err: FieldError::Width {
+                        expected: 17,
+                        actual: 18
+                    }
+                }
+            ),
+            "{strategy}: {err}",
+        );
+    }
+}
+
+#[test]
+fn empty_input() {
+    for (strategy, err) in errors("empty", "", &Config::default()) {
+        assert!(
+            matches!(err, Error::Header(HeaderError::Empty)),
+            "{strategy}: {err}",
+        );
+    }
+}
+
+#[test]
+fn byte_order_mark() {
+    let expected = run_all("no-bom", &format!("{HEADER}{SOURCES}"), &Config::default());
+    let contents = format!("\u{FEFF}{HEADER}{SOURCES}");
+    // The BOM would otherwise be part of the first column name.
+    let config = Config {
+        group_by: "RecordNo",
+        ..Config::default()
+    };
+    assert_eq!(run_all("bom-first-col", &contents, &config).len(), 4);
+    assert_eq!(run_all("bom", &contents, &Config::default()), expected);
+
+    let utf16 = format!("{HEADER}{SOURCES}")
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect::<Vec<_>>();
+    let path = fixture("utf16", "");
+    fs::write(&path, [&b"\xFF\xFE"[..], &utf16].concat()).unwrap();
+    for &strategy in STRATEGIES {
+        match run(strategy, &path, &Config::default()) {
+            Err(Error::Header(HeaderError::Utf16) | Error::Unsupported(_)) => {}
+            Err(err) => panic!("{strategy}: {err}"),
+            Ok(_) => panic!("{strategy} succeeded"),
+        }
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unknown_strategy() {
+    let path = fixture("unknown-strategy", format!("{HEADER}{SOURCES}"));
+    let err = run("nope", &path, &Config::default()).unwrap_err();
+    assert!(
+        matches!(&err, Error::UnknownStrategy(name) if name == "nope"),
+        "{err}"
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unrecognized_header() {
+    for (strategy, err) in errors("unrecognized", "a,b,c\n1,2,3\n", &Config::default()) {
+        assert!(
+            matches!(err, Error::Header(HeaderError::Unrecognized)),
+            "{strategy}: {err}",
+        );
+    }
+}
+
+#[test]
+fn case_insensitive_header() {
+    let expected = run_all(
+        "exact-case",
+        &format!("{HEADER}{SOURCES}"),
+        &Config::default(),
+    );
+    let contents = format!(
+        "{}{SOURCES}",
+        HEADER
+            .replace("Source", "SOURCE")
+            .replace("OrdQty", "ordqty")
+            .replace("Prod", "PROD"),
+    );
+    for (strategy, err) in errors("folded-case", &contents, &Config::default()) {
+        assert!(matches!(err, Error::Header(_)), "{strategy}: {err}");
+    }
+    let config = Config {
+        case_insensitive_header: true,
+        ..Config::default()
+    };
+    assert_eq!(run_all("folded-case", &contents, &config), expected);
+}
+
+#[test]
+fn header_names() {
+    let expected = run_all(
+        "default-names",
+        &format!("{HEADER}{SOURCES}"),
+        &Config::default(),
+    );
+    let contents = format!(
+        "{}{SOURCES}",
+        HEADER
+            .replace("Source", "Origin")
+            .replace("OrdQty", "Quantity"),
+    );
+    let config = Config {
+        header_names: vec![("Source", "Origin".into()), ("OrdQty", "Quantity".into())],
+        ..Config::default()
+    };
+    assert_eq!(run_all("renamed", &contents, &config), expected);
+    // Renamed columns are also matched ignoring case.
+    let config = Config {
+        case_insensitive_header: true,
+        ..config
+    };
+    let contents = contents.replace("Origin", "ORIGIN");
+    assert_eq!(run_all("renamed-case", &contents, &config), expected);
+}
+
+/// Loads a `--schema` file into the default config.
+fn schema(name: &str, text: &str) -> Result<Config, Error> {
+    let path = fixture(name, text);
+    let mut config = Config::default();
+    let res = load_schema(&path, &mut config);
+    fs::remove_file(&path).unwrap();
+    res.map(|()| config)
+}
+
+/// The line and error of a `--schema` file which fails to load.
+fn schema_error(name: &str, text: &str) -> (usize, SchemaError) {
+    match schema(name, text) {
+        Ok(_) => panic!("{name} loaded"),
+        Err(Error::Input { err, .. }) => match *err {
+            Error::Schema { line, err } => (line, err),
+            err => panic!("{name}: {err}"),
+        },
+        Err(err) => panic!("{name}: {err}"),
+    }
+}
+
+#[test]
+fn schema_values() {
+    let config = schema(
+        "schema-values",
+        r#"# An export with renamed columns
+
+source = "Origin"   # a comment after the value
+bs = "Side \"B\\S\""
+prod = "Symbol"
+qty = "Amount"
+buy_token = "B#"
+sell_token = "S"
+source_filter = "all"
+delimiter = "\t"
+"#,
+    )
+    .unwrap();
+    let header_names: Vec<_> = config
+        .header_names
+        .iter()
+        .map(|(name, header)| (*name, &**header))
+        .collect();
+    assert_eq!(
+        header_names,
+        [("Source", "Origin"), ("B/S", r#"Side "B\S""#)],
+    );
+    assert_eq!(config.group_by, "Symbol");
+    assert_eq!(config.qty_col, Some("Amount"));
+    assert_eq!(&*config.buy_token, b"B#");
+    assert_eq!(&*config.sell_token, b"S");
+    assert_eq!(config.source, None);
+    assert_eq!(config.delimiter, b'\t');
+}
+
+#[test]
+fn schema_errors() {
+    let cases = [
+        ("frob = 1", 1),
+        ("ordqty = true", 1),
+        ("no_header = \"yes\"", 1),
+        ("delimiter = \"ab\"", 1),
+        ("bs = B/S", 1),
+        ("bs \"B/S\"", 1),
+        ("bs = \"B/S", 1),
+        ("bs = \"B\\q\"", 1),
+        ("bs = \"B/S\" ordqty", 1),
+        ("prod = 13\nprod = \"Sym\"", 2),
+        ("# A header\n\nordqty = 3", 3),
+        ("no_header = true\nqty_index = 4", 2),
+    ];
+    let errors: Vec<_> = cases
+        .iter()
+        .enumerate()
+        .map(|(i, &(text, line))| {
+            let (actual, err) = schema_error(&format!("schema-error-{i}"), text);
+            assert_eq!(actual, line, "{text:?}: {err}");
+            err
+        })
+        .collect();
+    assert!(matches!(&errors[0], SchemaError::UnknownKey(key) if key == "frob"));
+    for err in &errors[1..5] {
+        assert!(matches!(err, SchemaError::InvalidValue(_)), "{err}");
+    }
+    for err in &errors[5..8] {
+        assert!(matches!(err, SchemaError::Syntax(_)), "{err}");
+    }
+    assert!(matches!(&errors[8], SchemaError::Trailing(rest) if rest == "ordqty"));
+    assert!(matches!(&errors[9], SchemaError::DuplicateKey(key) if key == "prod"));
+    assert!(matches!(&errors[10], SchemaError::IndexWithHeader(key) if key == "ordqty"));
+    assert!(matches!(errors[11], SchemaError::UnnamedQty));
+}
+
+#[test]
+fn schema_indices() {
+    let rows: String = SOURCES
+        .lines()
+        .skip(2)
+        .map(|row| format!("{row}\n"))
+        .collect();
+    let expected = run_all(
+        "schema-headed",
+        &format!("{HEADER}{rows}"),
+        &Config::default(),
+    );
+    // Indices are resolved after the whole file, so may precede `no_header`
+    // and the names of their columns.
+    let config = schema(
+        "schema-indices",
+        "source = 4\nbs = 9\nordqty = 10\nwrkqty = 11\nexcqty = 12\nprod = 13\nno_header = true\n",
+    )
+    .unwrap();
+    assert_eq!(run_all("schema-no-header", &rows, &config), expected);
+    let config = schema(
+        "schema-qty-index",
+        "qty_index = 11\nqty = \"Remaining\"\nno_header = true\n",
+    )
+    .unwrap();
+    assert_eq!(config.columns, [("Remaining".into(), 11)]);
+}
+
+#[test]
+fn no_header() {
+    // Starts with a `ToClnt` row, so that a first line taken as the header
+    // would drop a row.
+    let rows: String = SOURCES
+        .lines()
+        .skip(2)
+        .map(|row| format!("{row}\n"))
+        .collect();
+    let expected = run_all("headed", &format!("{HEADER}{rows}"), &Config::default());
+    let columns = [
+        ("Source", 4),
+        ("B/S", 9),
+        ("OrdQty", 10),
+        ("WrkQty", 11),
+        ("ExcQty", 12),
+        ("Prod", 13),
+    ];
+    let config = Config {
+        no_header: true,
+        columns: columns.map(|(name, i)| (name.into(), i)).to_vec(),
+        ..Config::default()
+    };
+    assert_eq!(run_all("no-header", &rows, &config), expected);
+    let config = Config {
+        strict: true,
+        ..config
+    };
+    for (strategy, err) in errors("no-header-strict", format!("{rows}a,b\n"), &config) {
+        assert!(
+            matches!(
+                err,
+                Error::Parse {
+                    line: 6,
+                    err: FieldError::Width {
+                        expected: 17,
+                        actual: 2
+                    }
+                }
+            ),
+            "{strategy}: {err}",
+        );
+    }
+}
+
+#[test]
+fn short_rows() {
+    let config = Config {
+        stats: true,
+        ..Config::default()
+    };
+    let expected = run_all("full-rows", &format!("{HEADER}{SOURCES}"), &config);
+    // Rows cut before each field, which are too short for the `Time` column
+    // of `--stats` at least, are counted as malformed.
+    let row = SOURCES.lines().nth(2).unwrap();
+    let cut: String = row
+        .match_indices(',')
+        .map(|(i, _)| format!("{}\n", &row[..i]))
+        .collect();
+    let results = run_all("short-rows", &format!("{HEADER}{cut}{SOURCES}"), &config);
+    assert_eq!(results, expected);
+
+    // With `--no-header`, the first row sets the width for `--strict`, so
+    // rows as short as it are not an error, but still cannot be aggregated.
+    let config = Config {
+        no_header: true,
+        strict: true,
+        columns: [
+            ("Source", 4),
+            ("B/S", 9),
+            ("OrdQty", 10),
+            ("WrkQty", 11),
+            ("ExcQty", 12),
+            ("Prod", 13),
+        ]
+        .map(|(name, i)| (name.into(), i))
+        .to_vec(),
+        ..Config::default()
+    };
+    let results = run_all(
+        "short-no-header",
+        "ToClnt,Buy,CAX\nToClnt,Sell,CAX\n",
+        &config,
+    );
+    assert_eq!(results, []);
+}
+
+#[test]
+fn skip_rows() {
+    let expected = run_all(
+        "unskipped",
+        &format!("{HEADER}{SOURCES}"),
+        &Config::default(),
+    );
+    let metadata = "# exported 2018-08-29\n# CME-C\n";
+    let config = Config {
+        skip_rows: 2,
+        ..Config::default()
+    };
+    let input = format!("{metadata}{HEADER}{SOURCES}");
+    assert_eq!(run_all("skip-rows", &input, &config), expected);
+    // Blocks smaller than the metadata make the skipped lines span several.
+    let small = Config {
+        buf_size: 8,
+        ..config.clone()
+    };
+    assert_eq!(run_all("skip-rows-small", &input, &small), expected);
+    let config = Config {
+        strict: true,
+        ..config
+    };
+    for (strategy, err) in errors("skip-rows-strict", format!("{input}a,b\n"), &config) {
+        assert!(
+            matches!(err, Error::Parse { line: 11, .. }),
+            "{strategy}: {err}",
+        );
+    }
+}
+
+#[test]
+fn group_by_side() {
+    let config = Config {
+        group_by_side: true,
+        ..Config::default()
+    };
+    // The reference strategy borrows keys, so cannot build composite keys.
+    let path = fixture("group-by-side", format!("{HEADER}{SOURCES}"));
+    let expected = run("read", &path, &config).unwrap();
+    for &strategy in STRATEGIES {
+        match run(strategy, &path, &config) {
+            Ok(actual) => assert_eq!(actual, expected, "{strategy} disagrees with read"),
+            Err(Error::Unsupported(_)) => {}
+            Err(err) => panic!("{strategy}: {err}"),
+        }
+    }
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        products(&expected),
+        [&b"CAX\0Buy"[..], b"CAX\0Sell", b"ZBX\0Buy", b"ZBX\0Sell"],
+    );
+    let totals: Vec<_> = expected.iter().map(|(_, data)| data.total_qty).collect();
+    assert_eq!(totals, [292, 10, 7, 50]);
+
+    let mut named = Config::default();
+    named.set_group_by("prod,bs");
+    assert_eq!((named.group_by, named.group_by_side), ("Prod", true));
+    let path = fixture("group-by-side-named", format!("{HEADER}{SOURCES}"));
+    assert_eq!(run("read", &path, &named).unwrap(), expected);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn group_by_names() {
+    let contents = format!("{HEADER}{SOURCES}");
+    let expected = run_all("group-by-default", &contents, &Config::default());
+    let mut config = Config::default();
+    config.set_group_by("prod");
+    assert_eq!((config.group_by, config.group_by_side), ("Prod", false));
+    assert_eq!(run_all("group-by-prod", &contents, &config), expected);
+    // Other built-in columns are also named in lowercase, and the rest by
+    // their header names.
+    let mut config = Config::default();
+    config.set_group_by("time");
+    assert_eq!(config.group_by, "Time");
+    assert_eq!(run_all("group-by-time", &contents, &config).len(), 4);
+    let mut config = Config::default();
+    config.set_group_by("Expiry");
+    assert_eq!(config.group_by, "Expiry");
+    assert_eq!(
+        products(&run_all("group-by-expiry", &contents, &config)),
+        [b"JUN19"]
+    );
+}
+
+#[test]
+fn normalize_keys() {
+    let config = Config {
+        normalize_keys: true,
+        prod: Some(b"zbx"[..].into()),
+        source: None,
+        ..Config::default()
+    };
+    // Mixes the case of each symbol, and leaves a blank symbol as <empty>.
+    let contents = format!(
+        "{HEADER}{}{}",
+        SOURCES.replace("ZBX", "zBx"),
+        SOURCES.replace("ZBX", ""),
+    );
+    let results = run_all("normalize-keys", &contents, &config);
+    assert_eq!(products(&results), [&b"ZBX"[..]]);
+    assert_eq!(results[0].1.count, 3);
+    let config = Config {
+        prod: None,
+        ..config
+    };
+    let results = run_all("normalize-keys", &contents, &config);
+    assert_eq!(products(&results), [&b"<empty>"[..], b"CAX", b"ZBX"]);
+    assert_eq!(results[1].1.count, 8);
+}
+
+#[test]
+fn prod_filter() {
+    let config = Config {
+        prod: Some(b"ZBX"[..].into()),
+        ..Config::default()
+    };
+    let results = run_all("prod", &format!("{HEADER}{SOURCES}"), &config);
+    assert_eq!(products(&results), [&b"ZBX"[..]]);
+
+    // Filtering echoes the matching lines after the header.
+    let path = fixture("filter", format!("{HEADER}{SOURCES}"));
+    let output = path.with_extension("out");
+    let config = Config {
+        output: Some(output.clone()),
+        ..config
+    };
+    let counts = filter(std::slice::from_ref(&path), &config).unwrap();
+    assert_eq!((counts.processed, counts.skipped), (2, 5));
+    let rows: String = SOURCES
+        .lines()
+        .skip(5)
+        .map(|row| format!("{row}\n"))
+        .collect();
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        format!("{HEADER}{rows}")
+    );
+    fs::remove_file(&output).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn source_summary_counts() {
+    let path = fixture("source-summary", format!("{HEADER}{SOURCES}"));
+    let output = path.with_extension("out");
+    let config = Config {
+        output: Some(output.clone()),
+        ..Config::default()
+    };
+    let counts = source_summary(std::slice::from_ref(&path), &config).unwrap();
+    assert_eq!(counts.processed, 7);
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        "ToClnt   4\nFrmClnt  1\nFromClnt 1\nToHst    1\n",
+    );
+    // Other filters still apply.
+    let config = Config {
+        prod: Some(b"ZBX"[..].into()),
+        ..config
+    };
+    source_summary(std::slice::from_ref(&path), &config).unwrap();
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        "ToClnt   2\nFromClnt 1\n",
+    );
+    fs::remove_file(&output).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn line_lengths() {
+    let path = fixture("line-stats", format!("{HEADER}a,b\n\nabcd\r\nabc"));
+    let output = path.with_extension("out");
+    let config = Config {
+        output: Some(output.clone()),
+        ..Config::default()
+    };
+    line_stats(std::slice::from_ref(&path), &config).unwrap();
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        "lines=4 blank=1 min=3 (line 2) max=4 (line 4) mean=3.33\n",
+    );
+    fs::remove_file(&output).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn follow() {
+    let rows: Vec<_> = SOURCES.lines().map(|row| format!("{row}\n")).collect();
+    let path = fixture("follow", format!("{HEADER}{}", rows[..3].concat()));
+    let config = Config {
+        follow: Some(Duration::from_millis(5)),
+        limit: rows.len(),
+        ..Config::default()
+    };
+    // The rest of the rows are appended later, the last in two parts.
+    let last = rows.last().unwrap();
+    let parts = [
+        rows[3..rows.len() - 1].concat(),
+        last[..4].to_owned(),
+        last[4..].to_owned(),
+    ];
+    let append = {
+        let path = path.clone();
+        thread::spawn(move || {
+            for part in parts {
+                thread::sleep(Duration::from_millis(50));
+                let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+                file.write_all(part.as_bytes()).unwrap();
+            }
+        })
+    };
+    let results = run("read", &path, &config).unwrap();
+    append.join().unwrap();
+    let expected = run_all(
+        "followed",
+        &format!("{HEADER}{SOURCES}"),
+        &Config::default(),
+    );
+    assert_eq!(results, expected);
+    // Only `read` follows inputs, and not compressed ones.
+    for strategy in ["fulltext", "custom-read"] {
+        assert!(matches!(
+            run(strategy, &path, &config),
+            Err(Error::Unsupported(_))
+        ));
+    }
+    let gzip = fixture("follow-gzip", b"\x1f\x8b");
+    assert!(matches!(
+        run("read", &gzip, &config),
+        Err(Error::Unsupported(_))
+    ));
+    fs::remove_file(&gzip).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn split_output() {
+    let dir = std::env::temp_dir().join(format!("cliff-stock-bench-{}-split", std::process::id()));
+    let contents = format!("{HEADER}{}", SOURCES.replace("ZBX", "Z/X"));
+    let path = fixture("split", &contents);
+    let config = Config {
+        split_output: Some(dir.clone()),
+        ..Config::default()
+    };
+    run_strategy(REFERENCE, std::slice::from_ref(&path), &config, &mut Print).unwrap();
+    let cax = fs::read_to_string(dir.join("CAX.txt")).unwrap();
+    assert!(cax.starts_with("CAX cnt=   2 "), "{cax}");
+    let zx = fs::read_to_string(dir.join("Z_X.txt")).unwrap();
+    assert!(zx.starts_with("Z/X cnt=   2 "), "{zx}");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Both products would be written to `Z_X.txt`.
+    fs::write(
+        &path,
+        format!("{contents}{}", SOURCES.replace("ZBX", "Z_X")),
+    )
+    .unwrap();
+    let err =
+        run_strategy(REFERENCE, std::slice::from_ref(&path), &config, &mut Print).unwrap_err();
+    assert!(err.to_string().contains("Z_X.txt"), "{err}");
+    // No product is written when any would collide.
+    assert!(!dir.exists());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bin_format() {
+    let contents = format!("{HEADER}{SOURCES}");
+    let expected = run_all("bin-expected", &contents, &Config::default());
+    let path = fixture("bin", &contents);
+    let output = path.with_extension("bin");
+    let config = Config {
+        format: Format::Bin,
+        sort: SortOrder::Symbol,
+        output: Some(output.clone()),
+        ..Config::default()
+    };
+    for &strategy in STRATEGIES {
+        run_strategy(strategy, std::slice::from_ref(&path), &config, &mut Print).unwrap();
+        let mut r = io::Cursor::new(fs::read(&output).unwrap());
+        let mut decoded = Vec::new();
+        while let Some(product) = read_bin_product(&mut r).unwrap() {
+            decoded.push(product);
+        }
+        assert_eq!(decoded.len(), expected.len(), "{strategy}");
+        for (product, (prod, data)) in decoded.iter().zip(&expected) {
+            assert_eq!(product.prod, *prod, "{strategy}");
+            assert_eq!(
+                (product.count, product.buys, product.sells),
+                (data.count, data.buys, data.sells),
+                "{strategy}",
+            );
+            assert_eq!(
+                (product.total_qty, product.max_qty),
+                (data.total_qty, data.max_qty),
+                "{strategy}",
+            );
+        }
+    }
+    // A truncated record is an error rather than the end of the input.
+    let bin = fs::read(&output).unwrap();
+    let mut r = io::Cursor::new(&bin[..bin.len() - 1]);
+    assert!(read_bin_product(&mut r).unwrap().is_some());
+    let err = read_bin_product(&mut r).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    fs::remove_file(&output).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn fields() {
+    let path = fixture("fields", format!("{HEADER}{SOURCES}"));
+    let output = path.with_extension("out");
+    let print = |format| {
+        let config = Config {
+            format,
+            fields: vec![OutputField::Sells, OutputField::Count, OutputField::Rate],
+            sort: SortOrder::Symbol,
+            output: Some(output.clone()),
+            ..Config::default()
+        };
+        run_strategy(REFERENCE, std::slice::from_ref(&path), &config, &mut Print).unwrap();
+        fs::read_to_string(&output).unwrap()
+    };
+    // The rate is unknown without `--stats`, which `--fields` would enable.
+    assert_eq!(
+        print(Format::Text),
+        "processed=4 skipped=3 blank=0 malformed=0 ignored=0\n\
+         CAX sell=   1 cnt=   2\n\
+         ZBX sell=   1 cnt=   2\n\
+         TOTAL sell=   2 cnt=   4\n",
+    );
+    assert_eq!(
+        print(Format::Json),
+        "[\n  {\"prod\":\"CAX\",\"sells\":1,\"count\":2},\n  \
+         {\"prod\":\"ZBX\",\"sells\":1,\"count\":2}\n]\n",
+    );
+    assert_eq!(
+        print(Format::Csv),
+        "Prod,Sells,Count,Rate\nCAX,1,2,\nZBX,1,2,\n",
+    );
+    assert_eq!(
+        OutputField::from_name("exc_total"),
+        Some(OutputField::ExcTotal)
+    );
+    assert_eq!(OutputField::from_name("bogus"), None);
+    fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn trace() {
+    let config = Config {
+        trace: true,
+        ..Config::default()
+    };
+    let contents = format!("{HEADER}{SOURCES}\n{HEADER}{SOURCES}");
+    let results = run_all("trace", &contents, &config);
+    let (cax, zbx) = (&results[0].1, &results[1].1);
+    // The second copy starts after a blank line and the repeated header.
+    assert_eq!((cax.first_line(), cax.last_line), (4, 14));
+    assert_eq!((zbx.first_line(), zbx.last_line), (7, 17));
+}
+
+#[test]
+fn order_rate() {
+    let config = Config {
+        stats: true,
+        ..Config::default()
+    };
+    let results = run_all("rate", &format!("{HEADER}{SOURCES}"), &config);
+    // CAX has a row at each of 09:00:11 and 09:00:12 and ZBX at 09:00:14 and
+    // 09:00:15.
+    assert_eq!(results[0].1.order_rate(), Some(2.0));
+    assert_eq!(results[1].1.order_rate(), Some(2.0));
+
+    // Epoch seconds work the same, and rows in the same second span one.
+    let header = "Prod,Source,B/S,OrdQty,WrkQty,ExcQty,Time\n";
+    let rows = "CAX,ToClnt,Buy,1,1,1,1535500824\n\
+        CAX,ToClnt,Buy,1,1,1,1535500824\n\
+        CAX,ToClnt,Buy,1,1,1,1535500824\n\
+        ZBX,ToClnt,Buy,1,1,1,1535500824\n\
+        ZBX,ToClnt,Buy,1,1,1,1535500828\n";
+    let results = run_all("rate-epoch", &format!("{header}{rows}"), &config);
+    assert_eq!(results[0].1.order_rate(), Some(3.0));
+    assert_eq!(results[1].1.order_rate(), Some(0.5));
+
+    // Without a Time column, there is no rate.
+    let results = run_all(
+        "rate-none",
+        "Prod,Source,B/S,OrdQty,WrkQty,ExcQty\nCAX,ToClnt,Buy,1,1,1\n",
+        &config,
+    );
+    assert_eq!(results[0].1.order_rate(), None);
+}
+
+#[test]
+fn validate_utf8() {
+    let mut contents = format!("{HEADER}{SOURCES}").into_bytes();
+    let i = contents.windows(3).rposition(|w| w == b"ZBX").unwrap();
+    contents[i + 1] = 0xff;
+    let config = Config {
+        validate_utf8: true,
+        ..Config::default()
+    };
+    for (strategy, err) in errors("validate-utf8", &contents, &config) {
+        assert!(
+            matches!(
+                err,
+                Error::Parse {
+                    line: 8,
+                    err: FieldError::Utf8 {
+                        col: "Prod",
+                        valid_up_to: 1,
+                        ..
+                    }
+                }
+            ),
+            "{strategy}: {err}",
+        );
+    }
+}